@@ -0,0 +1,126 @@
+use anyhow::Result;
+
+use crate::storage_backend::{CacheEntry, DynStorageBackend};
+use crate::{hash_files, tree_key};
+
+/// Name of the cache a key was generated for, the prefix before the commit, files or tree part:
+/// `<prefix>-<commit>[-<suffix>]`, `<prefix>-files-<sha256>[-<suffix>]` or `<prefix>-tree-<id>[-<suffix>]`.
+///
+/// A bare string prefix is not enough, `build` would also match the caches of `build-linux`.
+/// Keys given with `--fixed-key` have no generated part and no name.
+pub fn cache_name(key: &str) -> Option<&str> {
+    key.match_indices('-')
+        .map(|(index, _)| index)
+        .find(|&index| index > 0 && is_generated_part(&key[index + 1..]))
+        .map(|index| &key[..index])
+}
+
+/// Whether `key` was generated for the cache named `prefix`
+pub fn is_cache_of(key: &str, prefix: &str) -> bool {
+    key.strip_prefix(prefix)
+        .and_then(|rest| rest.strip_prefix('-'))
+        .is_some_and(is_generated_part)
+}
+
+/// Stored caches, only the ones of `prefix` if it is given: the keys generated for the cache named `prefix`
+/// and the fixed keys starting with `<prefix>-`. A fixed key has no name, `build-linux-nightly` could be
+/// the fixed key `linux-nightly` of `build` as well as `nightly` of `build-linux`, it is listed for both.
+pub fn list_caches(
+    backend: &dyn DynStorageBackend,
+    prefix: Option<&str>,
+) -> Result<Vec<CacheEntry>> {
+    let Some(prefix) = prefix else {
+        return backend.list("");
+    };
+    Ok(backend
+        .list(&format!("{}-", prefix))?
+        .into_iter()
+        .filter(|entry| is_cache_of(&entry.key, prefix) || cache_name(&entry.key).is_none())
+        .collect())
+}
+
+/// A commit, files or tree part, optionally followed by a suffix
+fn is_generated_part(part: &str) -> bool {
+    let hash = part
+        .strip_prefix(hash_files::KEY_PART_PREFIX)
+        .or_else(|| part.strip_prefix(tree_key::KEY_PART_PREFIX))
+        .unwrap_or(part);
+    let hash_len = hash
+        .find(|c: char| !matches!(c, '0'..='9' | 'a'..='f'))
+        .unwrap_or(hash.len());
+    // SHA-1 and SHA-256 object ids, the files part is always SHA-256
+    if hash_len != 40 && hash_len != 64 {
+        return false;
+    }
+    match &hash[hash_len..] {
+        "" => true,
+        rest => rest
+            .strip_prefix('-')
+            .is_some_and(|suffix| !suffix.is_empty()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::folder_backend::FolderBackend;
+    use crate::storage_backend::{StorageBackend, StorageWriter};
+    use std::io::Write;
+
+    const COMMIT: &str = "87290fc479c6fca21d6f369635ed8ffea5438a30";
+    const SHA256: &str = "da5ed0ee29db7076f0ebc0ab7d42c12522ca68a628fe8614b76511e71989c4e0";
+
+    #[test]
+    fn finds_the_name_of_generated_keys() {
+        assert_eq!(cache_name(&format!("build-{}", COMMIT)), Some("build"));
+        assert_eq!(
+            cache_name(&format!("build-linux-{}-x86_64", COMMIT)),
+            Some("build-linux")
+        );
+        assert_eq!(cache_name(&format!("deps-files-{}", SHA256)), Some("deps"));
+        assert_eq!(
+            cache_name(&format!("deps-tree-{}-release", COMMIT)),
+            Some("deps")
+        );
+        assert_eq!(cache_name("build-nightly"), None);
+        assert_eq!(cache_name(&format!("build-{}", &COMMIT[..12])), None);
+        assert_eq!(cache_name(&format!("-{}", COMMIT)), None);
+    }
+
+    #[test]
+    fn prefix_does_not_match_longer_names() {
+        let key = format!("build-linux-{}", COMMIT);
+        assert!(is_cache_of(&key, "build-linux"));
+        assert!(!is_cache_of(&key, "build"));
+        assert!(!is_cache_of(&key, "build-lin"));
+        assert!(is_cache_of(&format!("build-{}-linux", COMMIT), "build"));
+        assert!(!is_cache_of(&format!("build-{}-", COMMIT), "build"));
+        assert!(!is_cache_of(&format!("build-{}x", COMMIT), "build"));
+    }
+
+    #[test]
+    fn lists_generated_and_fixed_keys_of_a_prefix() {
+        let dir = tempfile::tempdir().unwrap();
+        let backend = FolderBackend::new(dir.path().to_path_buf());
+        let keys = [
+            format!("build-{}", COMMIT),
+            "build-nightly".to_string(),
+            format!("build-linux-{}", COMMIT),
+            format!("builder-{}", COMMIT),
+        ];
+        for key in &keys {
+            let mut writer = StorageBackend::writer(&backend, key).unwrap();
+            writer.write_all(b"cache").unwrap();
+            writer.finish().unwrap();
+        }
+
+        let mut listed = list_caches(&backend, Some("build"))
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.key)
+            .collect::<Vec<_>>();
+        listed.sort();
+        assert_eq!(listed, [keys[0].as_str(), "build-nightly"]);
+        assert_eq!(list_caches(&backend, None).unwrap().len(), keys.len());
+    }
+}
@@ -0,0 +1,319 @@
+use log::debug;
+
+/// CI systems whose merge/pull request pipelines are recognised
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CiProvider {
+    GitHub,
+    /// Gitea and Forgejo Actions, compatible with GitHub Actions
+    Gitea,
+    GitLab,
+    Buildkite,
+    Jenkins,
+}
+
+/// What the CI tells us about the current job
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct CiContext {
+    pub provider: Option<CiProvider>,
+    /// The job runs for a merge/pull request, the checked out commit may be a merge commit
+    pub merge_request: bool,
+    /// Number of the merge/pull request
+    pub merge_request_id: Option<String>,
+    /// Branch the merge/pull request will be merged into
+    pub target_branch: Option<String>,
+    /// Branch built, outside of merge/pull requests
+    pub branch: Option<String>,
+    /// Tag built
+    pub tag: Option<String>,
+}
+
+impl CiContext {
+    /// Detect the CI from the environment variables of the job
+    pub fn detect() -> Self {
+        let context = Self::from_env(|name| std::env::var(name).ok().filter(|v| !v.is_empty()));
+        if let Some(provider) = context.provider {
+            debug!(
+                "Running in {:?} CI, merge request: {}, target branch: {:?}",
+                provider, context.merge_request, context.target_branch
+            );
+        }
+        context
+    }
+
+    fn from_env(var: impl Fn(&str) -> Option<String>) -> Self {
+        let is_true = |name: &str| var(name).is_some_and(|v| v == "true");
+
+        // Gitea and Forgejo also set the GITHUB_* variables, check them first
+        if is_true("GITEA_ACTIONS") || is_true("FORGEJO_ACTIONS") {
+            return Self::github_like(CiProvider::Gitea, &var);
+        }
+        if is_true("GITHUB_ACTIONS") || var("GITHUB_REF").is_some() {
+            return Self::github_like(CiProvider::GitHub, &var);
+        }
+        if is_true("GITLAB_CI") {
+            return Self {
+                provider: Some(CiProvider::GitLab),
+                merge_request: var("CI_MERGE_REQUEST_IID").is_some(),
+                merge_request_id: var("CI_MERGE_REQUEST_IID"),
+                target_branch: var("CI_MERGE_REQUEST_TARGET_BRANCH_NAME"),
+                // Not set for tags and merge request pipelines
+                branch: var("CI_COMMIT_BRANCH"),
+                tag: var("CI_COMMIT_TAG"),
+            };
+        }
+        if is_true("BUILDKITE") {
+            let pull_request = var("BUILDKITE_PULL_REQUEST").filter(|v| v != "false");
+            return Self {
+                provider: Some(CiProvider::Buildkite),
+                merge_request: pull_request.is_some(),
+                target_branch: var("BUILDKITE_PULL_REQUEST_BASE_BRANCH"),
+                branch: var("BUILDKITE_BRANCH").filter(|_| pull_request.is_none()),
+                tag: var("BUILDKITE_TAG"),
+                merge_request_id: pull_request,
+            };
+        }
+        if var("JENKINS_URL").is_some() {
+            return Self {
+                provider: Some(CiProvider::Jenkins),
+                merge_request: var("CHANGE_ID").is_some(),
+                merge_request_id: var("CHANGE_ID"),
+                target_branch: var("CHANGE_TARGET"),
+                // Multibranch pipelines set it to PR-<id> for pull requests
+                branch: var("BRANCH_NAME").filter(|_| var("CHANGE_ID").is_none()),
+                tag: var("TAG_NAME"),
+            };
+        }
+
+        Self::default()
+    }
+
+    fn github_like(provider: CiProvider, var: &impl Fn(&str) -> Option<String>) -> Self {
+        let github_ref = var("GITHUB_REF");
+        let merge_request = github_ref
+            .as_ref()
+            .is_some_and(|r| r.contains("refs/pull/"))
+            || var("GITHUB_EVENT_NAME")
+                .is_some_and(|e| e == "pull_request" || e == "pull_request_target");
+        let (merge_request_id, branch) = match &github_ref {
+            // refs/pull/<number>/merge
+            Some(r) if r.starts_with("refs/pull/") => (
+                r.trim_start_matches("refs/pull/")
+                    .split('/')
+                    .next()
+                    .map(str::to_string),
+                None,
+            ),
+            Some(r) if !merge_request => (None, r.strip_prefix("refs/heads/").map(str::to_string)),
+            _ => (None, None),
+        };
+        Self {
+            provider: Some(provider),
+            merge_request,
+            merge_request_id,
+            // Only set for pull request events
+            target_branch: var("GITHUB_BASE_REF"),
+            branch,
+            tag: github_ref
+                .as_ref()
+                .and_then(|r| r.strip_prefix("refs/tags/"))
+                .map(str::to_string),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_vars(vars: &[(&str, &str)]) -> CiContext {
+        CiContext::from_env(|name| {
+            vars.iter()
+                .find(|(var, _)| *var == name)
+                .map(|(_, value)| value.to_string())
+        })
+    }
+
+    #[test]
+    fn detects_ci_context() {
+        let some = |value: &str| Some(value.to_string());
+        let cases = [
+            ("no CI", vec![("HOME", "/root")], CiContext::default()),
+            (
+                "GitHub push",
+                vec![
+                    ("GITHUB_ACTIONS", "true"),
+                    ("GITHUB_EVENT_NAME", "push"),
+                    ("GITHUB_REF", "refs/heads/main"),
+                ],
+                CiContext {
+                    provider: Some(CiProvider::GitHub),
+                    branch: some("main"),
+                    ..CiContext::default()
+                },
+            ),
+            (
+                "GitHub pull request",
+                vec![
+                    ("GITHUB_ACTIONS", "true"),
+                    ("GITHUB_EVENT_NAME", "pull_request"),
+                    ("GITHUB_REF", "refs/pull/42/merge"),
+                    ("GITHUB_BASE_REF", "main"),
+                ],
+                CiContext {
+                    provider: Some(CiProvider::GitHub),
+                    merge_request: true,
+                    merge_request_id: some("42"),
+                    target_branch: some("main"),
+                    ..CiContext::default()
+                },
+            ),
+            (
+                "GitHub tag",
+                vec![("GITHUB_ACTIONS", "true"), ("GITHUB_REF", "refs/tags/v1.0")],
+                CiContext {
+                    provider: Some(CiProvider::GitHub),
+                    tag: some("v1.0"),
+                    ..CiContext::default()
+                },
+            ),
+            (
+                "Forgejo pull request",
+                vec![
+                    ("FORGEJO_ACTIONS", "true"),
+                    ("GITHUB_ACTIONS", "true"),
+                    ("GITHUB_REF", "refs/pull/7/head"),
+                    ("GITHUB_BASE_REF", "main"),
+                ],
+                CiContext {
+                    provider: Some(CiProvider::Gitea),
+                    merge_request: true,
+                    merge_request_id: some("7"),
+                    target_branch: some("main"),
+                    ..CiContext::default()
+                },
+            ),
+            (
+                "GitLab branch",
+                vec![("GITLAB_CI", "true"), ("CI_COMMIT_BRANCH", "feature")],
+                CiContext {
+                    provider: Some(CiProvider::GitLab),
+                    branch: some("feature"),
+                    ..CiContext::default()
+                },
+            ),
+            (
+                "GitLab tag",
+                vec![("GITLAB_CI", "true"), ("CI_COMMIT_TAG", "v2.0")],
+                CiContext {
+                    provider: Some(CiProvider::GitLab),
+                    tag: some("v2.0"),
+                    ..CiContext::default()
+                },
+            ),
+            (
+                "GitLab merge request",
+                vec![
+                    ("GITLAB_CI", "true"),
+                    ("CI_MERGE_REQUEST_IID", "12"),
+                    ("CI_MERGE_REQUEST_TARGET_BRANCH_NAME", "develop"),
+                ],
+                CiContext {
+                    provider: Some(CiProvider::GitLab),
+                    merge_request: true,
+                    merge_request_id: some("12"),
+                    target_branch: some("develop"),
+                    ..CiContext::default()
+                },
+            ),
+            (
+                "Buildkite branch",
+                vec![
+                    ("BUILDKITE", "true"),
+                    ("BUILDKITE_BRANCH", "feature"),
+                    ("BUILDKITE_PULL_REQUEST", "false"),
+                ],
+                CiContext {
+                    provider: Some(CiProvider::Buildkite),
+                    branch: some("feature"),
+                    ..CiContext::default()
+                },
+            ),
+            (
+                "Buildkite pull request",
+                vec![
+                    ("BUILDKITE", "true"),
+                    ("BUILDKITE_BRANCH", "feature"),
+                    ("BUILDKITE_PULL_REQUEST", "5"),
+                    ("BUILDKITE_PULL_REQUEST_BASE_BRANCH", "main"),
+                ],
+                CiContext {
+                    provider: Some(CiProvider::Buildkite),
+                    merge_request: true,
+                    merge_request_id: some("5"),
+                    target_branch: some("main"),
+                    ..CiContext::default()
+                },
+            ),
+            (
+                "Buildkite tag",
+                vec![
+                    ("BUILDKITE", "true"),
+                    ("BUILDKITE_BRANCH", "v3.0"),
+                    ("BUILDKITE_TAG", "v3.0"),
+                    ("BUILDKITE_PULL_REQUEST", "false"),
+                ],
+                CiContext {
+                    provider: Some(CiProvider::Buildkite),
+                    branch: some("v3.0"),
+                    tag: some("v3.0"),
+                    ..CiContext::default()
+                },
+            ),
+            (
+                "Jenkins branch",
+                vec![
+                    ("JENKINS_URL", "https://jenkins.example.com/"),
+                    ("BRANCH_NAME", "feature"),
+                ],
+                CiContext {
+                    provider: Some(CiProvider::Jenkins),
+                    branch: some("feature"),
+                    ..CiContext::default()
+                },
+            ),
+            (
+                "Jenkins pull request",
+                vec![
+                    ("JENKINS_URL", "https://jenkins.example.com/"),
+                    ("BRANCH_NAME", "PR-9"),
+                    ("CHANGE_ID", "9"),
+                    ("CHANGE_TARGET", "main"),
+                ],
+                CiContext {
+                    provider: Some(CiProvider::Jenkins),
+                    merge_request: true,
+                    merge_request_id: some("9"),
+                    target_branch: some("main"),
+                    ..CiContext::default()
+                },
+            ),
+            (
+                "Jenkins tag",
+                vec![
+                    ("JENKINS_URL", "https://jenkins.example.com/"),
+                    ("BRANCH_NAME", "v4.0"),
+                    ("TAG_NAME", "v4.0"),
+                ],
+                CiContext {
+                    provider: Some(CiProvider::Jenkins),
+                    branch: some("v4.0"),
+                    tag: some("v4.0"),
+                    ..CiContext::default()
+                },
+            ),
+        ];
+        for (name, vars, expected) in cases {
+            assert_eq!(from_vars(&vars), expected, "{}", name);
+        }
+    }
+}
@@ -0,0 +1,194 @@
+use clap::ValueEnum;
+use flate2::write::GzEncoder;
+use log::debug;
+use std::io::{self, Cursor, Read, Write};
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+    None,
+}
+
+/// Compresses the archive with the selected format.
+pub enum Encoder<W: Write> {
+    Gzip(GzEncoder<W>),
+    Zstd(zstd::Encoder<'static, W>),
+    None(W),
+}
+
+impl<W: Write> Encoder<W> {
+    /// Create an encoder, the default level of the format is used if `level` is not set.
+    pub fn new(writer: W, compression: Compression, level: Option<i32>) -> io::Result<Self> {
+        match compression {
+            Compression::Gzip => {
+                let level = level.unwrap_or(6);
+                if !(0..=9).contains(&level) {
+                    return Err(invalid_level(compression, level));
+                }
+                debug!("Compressing with gzip level {}", level);
+                Ok(Self::Gzip(GzEncoder::new(
+                    writer,
+                    flate2::Compression::new(level as u32),
+                )))
+            }
+            Compression::Zstd => {
+                let level = level.unwrap_or(zstd::DEFAULT_COMPRESSION_LEVEL);
+                if !zstd::compression_level_range().contains(&level) {
+                    return Err(invalid_level(compression, level));
+                }
+                let workers = std::thread::available_parallelism()
+                    .map(|n| n.get() as u32)
+                    .unwrap_or(1);
+                debug!(
+                    "Compressing with zstd level {} using {} workers",
+                    level, workers
+                );
+                let mut encoder = zstd::Encoder::new(writer, level)?;
+                encoder.multithread(workers)?;
+                Ok(Self::Zstd(encoder))
+            }
+            Compression::None => {
+                if let Some(level) = level {
+                    return Err(invalid_level(compression, level));
+                }
+                Ok(Self::None(writer))
+            }
+        }
+    }
+
+    /// Write the end of the compressed stream and return the inner writer.
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            Self::Gzip(encoder) => encoder.finish(),
+            Self::Zstd(encoder) => encoder.finish(),
+            Self::None(writer) => Ok(writer),
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Gzip(encoder) => encoder.write(buf),
+            Self::Zstd(encoder) => encoder.write(buf),
+            Self::None(writer) => writer.write(buf),
+        }
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Gzip(encoder) => encoder.flush(),
+            Self::Zstd(encoder) => encoder.flush(),
+            Self::None(writer) => writer.flush(),
+        }
+    }
+}
+
+/// Detect the compression of the stream from its magic bytes and decompress it.
+/// Anything that is not gzip or zstd is read as an uncompressed tar archive.
+pub fn decoder<'a, R: Read + 'a>(mut reader: R) -> io::Result<Box<dyn Read + 'a>> {
+    let mut magic = [0u8; 4];
+    let mut read = 0;
+    while read < magic.len() {
+        match reader.read(&mut magic[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        }
+    }
+    let magic = &magic[..read];
+    let reader = Cursor::new(magic.to_vec()).chain(reader);
+
+    if magic.starts_with(&ZSTD_MAGIC) {
+        debug!("Archive is compressed with zstd");
+        Ok(Box::new(zstd::Decoder::new(reader)?))
+    } else if magic.starts_with(&GZIP_MAGIC) {
+        debug!("Archive is compressed with gzip");
+        Ok(Box::new(flate2::read::GzDecoder::new(reader)))
+    } else {
+        debug!("Archive is not compressed");
+        Ok(Box::new(reader))
+    }
+}
+
+fn invalid_level(compression: Compression, level: i32) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("Invalid level {} for {:?} compression", level, compression),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a byte per read, like a slow stream
+    struct ByteReader<R>(R);
+
+    impl<R: Read> Read for ByteReader<R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = buf.len().min(1);
+            self.0.read(&mut buf[..len])
+        }
+    }
+
+    fn compress(data: &[u8], compression: Compression, level: Option<i32>) -> Vec<u8> {
+        let mut encoder = Encoder::new(Vec::new(), compression, level).unwrap();
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn decompress(reader: impl Read) -> Vec<u8> {
+        let mut data = Vec::new();
+        decoder(reader).unwrap().read_to_end(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn decodes_what_is_encoded() {
+        let long: Vec<u8> = (0..100_000u32)
+            .flat_map(|n| (n % 251).to_le_bytes())
+            .collect();
+        // Shorter than the zstd magic, or starting like it
+        let inputs: [&[u8]; 5] = [b"", b"a", b"\x28\xb5", b"tar", &long];
+        for compression in [Compression::Gzip, Compression::Zstd, Compression::None] {
+            for input in inputs {
+                let compressed = compress(input, compression, None);
+                assert_eq!(
+                    decompress(compressed.as_slice()),
+                    input,
+                    "{:?}",
+                    compression
+                );
+                assert_eq!(
+                    decompress(ByteReader(compressed.as_slice())),
+                    input,
+                    "{:?} read a byte at a time",
+                    compression
+                );
+            }
+        }
+        assert_eq!(compress(&long, Compression::None, None), long);
+        assert!(compress(&long, Compression::Zstd, Some(19)).len() < long.len());
+        assert!(compress(&long, Compression::Gzip, Some(9)).len() < long.len());
+    }
+
+    #[test]
+    fn rejects_invalid_levels() {
+        for (compression, level) in [
+            (Compression::Gzip, 10),
+            (Compression::Gzip, -1),
+            (Compression::Zstd, 100),
+            (Compression::None, 1),
+        ] {
+            let Err(err) = Encoder::new(Vec::new(), compression, Some(level)) else {
+                panic!("{:?} accepted level {}", compression, level);
+            };
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+}
@@ -0,0 +1,74 @@
+use anyhow::{Result, anyhow, bail};
+use log::debug;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+/// Name of the configuration file, at the root of the repository
+pub const CONFIG_FILE: &str = "cache-thing.toml";
+
+/// Caches defined in `cache-thing.toml`, so every step of a workflow uses the same definition:
+///
+/// ```toml
+/// [caches.build]
+/// paths = ["target"]
+/// exclude = ["target/**/incremental"]
+/// hash-files = ["Cargo.lock"]
+/// location = "s3://bucket/caches"
+/// fallback-keys = ["nightly"]
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub caches: BTreeMap<String, CacheConfig>,
+}
+
+/// Definition of a named cache, the command line arguments take precedence
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct CacheConfig {
+    /// Defaults to the name of the cache
+    pub prefix: Option<String>,
+    #[serde(default)]
+    pub paths: Vec<String>,
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    pub suffix: Option<String>,
+    pub fixed_key: Option<String>,
+    #[serde(default)]
+    pub hash_files: Vec<String>,
+    #[serde(default)]
+    pub tree_paths: Vec<String>,
+    /// Same values as `--compression`
+    pub compression: Option<String>,
+    pub level: Option<i32>,
+    pub location: Option<String>,
+    /// Same values as `--backend`
+    pub backend: Option<String>,
+    #[serde(default)]
+    pub fallback_keys: Vec<String>,
+    #[serde(default)]
+    pub restore_keys: Vec<String>,
+}
+
+impl Config {
+    /// Read the configuration file at the root of the current repository
+    pub fn load() -> Result<Self> {
+        let repository = gix::discover(".")?;
+        let Some(workdir) = repository.workdir() else {
+            bail!("Can't read {} in a bare repository", CONFIG_FILE);
+        };
+        let path = workdir.join(CONFIG_FILE);
+        debug!("Reading configuration from {:?}", path);
+        let content = std::fs::read_to_string(&path)
+            .map_err(|err| anyhow!("Could not read {}: {}", path.display(), err))?;
+        toml::from_str(&content).map_err(|err| anyhow!("Invalid {}: {}", path.display(), err))
+    }
+
+    pub fn cache(&self, name: &str) -> Result<&CacheConfig> {
+        match self.caches.get(name) {
+            Some(cache) => Ok(cache),
+            None => bail!("No cache named {} in {}", name, CONFIG_FILE),
+        }
+    }
+}
@@ -0,0 +1,257 @@
+use gix::bstr::ByteSlice;
+use gix::glob::wildmatch;
+use gix::{AttributeStack, Repository};
+use log::{debug, trace};
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+/// Attribute excluding paths from the caches, set in `.gitattributes`:
+///
+/// ```text
+/// target/**/incremental cache-thing-exclude
+/// *.pdb cache-thing-exclude
+/// ```
+pub const ATTRIBUTE: &str = "cache-thing-exclude";
+
+/// Files left out of the archives, by `--exclude` patterns or by the attribute.
+/// A directory that is excluded is left out with its content.
+pub struct Exclusions<'r> {
+    patterns: Vec<String>,
+    attributes: Option<Attributes<'r>>,
+}
+
+struct Attributes<'r> {
+    stack: AttributeStack<'r>,
+    outcome: gix::attrs::search::Outcome,
+    /// Current directory relative to the root of the repository
+    current_dir: PathBuf,
+}
+
+impl<'r> Exclusions<'r> {
+    /// The attribute is only read if the current directory is in the working tree of `repository`
+    pub fn new(repository: Option<&'r Repository>, patterns: &[String]) -> io::Result<Self> {
+        Self::in_dir(repository, patterns, &std::env::current_dir()?)
+    }
+
+    fn in_dir(
+        repository: Option<&'r Repository>,
+        patterns: &[String],
+        current_dir: &Path,
+    ) -> io::Result<Self> {
+        let attributes = match repository {
+            Some(repository) => Attributes::new(repository, current_dir)?,
+            None => None,
+        };
+        Ok(Self {
+            patterns: patterns.to_vec(),
+            attributes,
+        })
+    }
+
+    /// Whether `path`, relative to the current directory, is left out of the archive
+    pub fn is_excluded(&mut self, path: &Path, is_dir: bool) -> io::Result<bool> {
+        let unix_path = gix::path::to_unix_separators_on_windows(gix::path::into_bstr(path));
+        let unix_path = unix_path.strip_prefix(b"./").unwrap_or(&unix_path);
+        if let Some(pattern) = self.patterns.iter().find(|pattern| {
+            wildmatch(
+                pattern.as_bytes().as_bstr(),
+                unix_path.as_bstr(),
+                wildmatch::Mode::NO_MATCH_SLASH_LITERAL,
+            )
+        }) {
+            trace!("Excluding {:?}, it matches {}", path, pattern);
+            return Ok(true);
+        }
+
+        let Some(attributes) = &mut self.attributes else {
+            return Ok(false);
+        };
+        let Some(relative) = attributes.repository_path(path) else {
+            return Ok(false);
+        };
+        let mode = if is_dir {
+            gix::index::entry::Mode::DIR
+        } else {
+            gix::index::entry::Mode::FILE
+        };
+        let platform = attributes.stack.at_path(&relative, Some(mode))?;
+        platform.matching_attributes(&mut attributes.outcome);
+        let excluded = attributes
+            .outcome
+            .iter_selected()
+            .any(|matched| matched.assignment.state.is_set());
+        if excluded {
+            trace!("Excluding {:?}, it has the {} attribute", path, ATTRIBUTE);
+        }
+        Ok(excluded)
+    }
+}
+
+impl<'r> Attributes<'r> {
+    fn new(repository: &'r Repository, current_dir: &Path) -> io::Result<Option<Self>> {
+        let Some(workdir) = repository.workdir() else {
+            return Ok(None);
+        };
+        let Ok(current_dir) = current_dir
+            .canonicalize()?
+            .strip_prefix(workdir.canonicalize()?)
+            .map(Path::to_path_buf)
+        else {
+            debug!(
+                "Not in the working tree, the {} attribute is ignored",
+                ATTRIBUTE
+            );
+            return Ok(None);
+        };
+
+        let index = repository.index_or_empty().map_err(io::Error::other)?;
+        let stack = repository
+            .attributes_only(
+                &index,
+                gix::worktree::stack::state::attributes::Source::WorktreeThenIdMapping,
+            )
+            .map_err(io::Error::other)?;
+        let outcome = stack.selected_attribute_matches([ATTRIBUTE]);
+        Ok(Some(Self {
+            stack,
+            outcome,
+            current_dir,
+        }))
+    }
+
+    /// Path relative to the root of the repository, `None` if it is outside of it
+    fn repository_path(&self, path: &Path) -> Option<PathBuf> {
+        let mut relative = self.current_dir.clone();
+        for component in path.components() {
+            match component {
+                Component::Normal(name) => relative.push(name),
+                Component::CurDir => {}
+                Component::ParentDir => {
+                    if !relative.pop() {
+                        return None;
+                    }
+                }
+                Component::RootDir | Component::Prefix(_) => return None,
+            }
+        }
+        Some(relative)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::{self, Manifest};
+
+    fn excluded(exclusions: &mut Exclusions, path: &str, is_dir: bool) -> bool {
+        exclusions.is_excluded(Path::new(path), is_dir).unwrap()
+    }
+
+    #[test]
+    fn excludes_paths_matching_the_patterns() {
+        let mut exclusions = Exclusions::new(None, &["target/**/incremental".to_string()]).unwrap();
+        // As found under `-f target` and `-f ./target`
+        assert!(excluded(&mut exclusions, "target/debug/incremental", true));
+        assert!(excluded(
+            &mut exclusions,
+            "./target/debug/incremental",
+            true
+        ));
+        assert!(excluded(&mut exclusions, "target/incremental", true));
+        assert!(excluded(
+            &mut exclusions,
+            "target/x86_64-unknown-linux-gnu/release/incremental",
+            true
+        ));
+        assert!(!excluded(&mut exclusions, "target/debug", true));
+        assert!(!excluded(
+            &mut exclusions,
+            "target/debug/incremental.d",
+            false
+        ));
+        assert!(!excluded(&mut exclusions, "other/debug/incremental", true));
+    }
+
+    #[test]
+    fn excludes_paths_with_the_attribute() {
+        let dir = tempfile::tempdir().unwrap();
+        let repository = gix::init(dir.path()).unwrap();
+        std::fs::write(
+            dir.path().join(".gitattributes"),
+            "target/**/incremental cache-thing-exclude\n*.pdb cache-thing-exclude\n",
+        )
+        .unwrap();
+
+        let mut exclusions = Exclusions::in_dir(Some(&repository), &[], dir.path()).unwrap();
+        assert!(excluded(&mut exclusions, "target/debug/incremental", true));
+        assert!(excluded(
+            &mut exclusions,
+            "./target/debug/incremental",
+            true
+        ));
+        assert!(excluded(&mut exclusions, "target/debug/app.pdb", false));
+        assert!(!excluded(&mut exclusions, "target/debug/app", false));
+
+        // Paths are relative to the current directory
+        let sub_dir = dir.path().join("target");
+        std::fs::create_dir(&sub_dir).unwrap();
+        let mut exclusions = Exclusions::in_dir(Some(&repository), &[], &sub_dir).unwrap();
+        assert!(excluded(&mut exclusions, "debug/incremental", true));
+        assert!(excluded(
+            &mut exclusions,
+            "../target/debug/incremental",
+            true
+        ));
+        assert!(!excluded(&mut exclusions, "../debug/incremental", true));
+        assert!(!excluded(
+            &mut exclusions,
+            "../../target/debug/incremental",
+            true
+        ));
+
+        // Ignored outside of the working tree
+        let outside = tempfile::tempdir().unwrap();
+        let mut exclusions = Exclusions::in_dir(Some(&repository), &[], outside.path()).unwrap();
+        assert!(!excluded(&mut exclusions, "target/debug/incremental", true));
+    }
+
+    #[test]
+    fn leaves_excluded_files_out_of_the_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("target");
+        std::fs::create_dir_all(target.join("debug/incremental/app")).unwrap();
+        std::fs::write(target.join("debug/app"), b"app").unwrap();
+        std::fs::write(target.join("debug/incremental/app/query"), b"query").unwrap();
+        let target = target.to_str().unwrap();
+
+        let mut all = Exclusions::new(None, &[]).unwrap();
+        let pattern = format!("{}/**/incremental", target);
+        let mut exclusions = Exclusions::new(None, &[pattern]).unwrap();
+
+        let mut manifest = Manifest::new(None);
+        let entry = manifest
+            .add_path(target, "0123".to_string(), None, &mut all)
+            .unwrap();
+        assert_eq!((entry.files, entry.size), (6, 8));
+        let entry = manifest
+            .add_path(target, "4567".to_string(), None, &mut exclusions)
+            .unwrap();
+        assert_eq!((entry.files, entry.size), (3, 3));
+
+        let digest = manifest::path_digest(Path::new(target), &mut exclusions).unwrap();
+        assert_ne!(
+            digest,
+            manifest::path_digest(Path::new(target), &mut all).unwrap()
+        );
+        // The excluded files don't change the digest
+        std::fs::write(
+            dir.path().join("target/debug/incremental/app/query"),
+            b"changed",
+        )
+        .unwrap();
+        assert_eq!(
+            digest,
+            manifest::path_digest(Path::new(target), &mut exclusions).unwrap()
+        );
+    }
+}
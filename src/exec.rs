@@ -0,0 +1,105 @@
+use anyhow::{Result, bail};
+use log::debug;
+use std::process::{Command, ExitStatus};
+
+/// Run `command` and wait for it, forwarding the termination signals we receive to it.
+/// Returns the exit code of the command, 128 + the signal number if it was killed by a signal.
+pub fn run(command: &[String]) -> Result<i32> {
+    let Some((program, args)) = command.split_first() else {
+        bail!("No command to run");
+    };
+
+    debug!("Running {:?}", command);
+    // Installed before spawning, a job canceled while the command starts must still stop it
+    #[cfg(unix)]
+    signals::install();
+    let child = Command::new(program).args(args).spawn();
+    let mut child = match child {
+        Ok(child) => child,
+        Err(err) => {
+            #[cfg(unix)]
+            signals::restore();
+            return Err(err.into());
+        }
+    };
+
+    #[cfg(unix)]
+    signals::forward_to(child.id());
+    let status = child.wait();
+    #[cfg(unix)]
+    signals::restore();
+
+    let status = status?;
+    debug!("Command exited with {}", status);
+    Ok(exit_code(status))
+}
+
+fn exit_code(status: ExitStatus) -> i32 {
+    if let Some(code) = status.code() {
+        return code;
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(signal) = status.signal() {
+            return 128 + signal;
+        }
+    }
+    1
+}
+
+/// CI runners cancel a job by signaling the main process only,
+/// the command must get the signal to stop and let us exit.
+#[cfg(unix)]
+mod signals {
+    use std::sync::atomic::{AtomicI32, Ordering};
+
+    const FORWARDED: [libc::c_int; 4] = [libc::SIGINT, libc::SIGTERM, libc::SIGHUP, libc::SIGQUIT];
+
+    static CHILD: AtomicI32 = AtomicI32::new(0);
+
+    /// Signal received before the command was started
+    static PENDING: AtomicI32 = AtomicI32::new(0);
+
+    extern "C" fn forward(signal: libc::c_int) {
+        let pid = CHILD.load(Ordering::SeqCst);
+        if pid > 0 {
+            // kill is async-signal-safe
+            unsafe {
+                libc::kill(pid, signal);
+            }
+        } else {
+            PENDING.store(signal, Ordering::SeqCst);
+        }
+    }
+
+    pub fn install() {
+        let handler = forward as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        for signal in FORWARDED {
+            unsafe {
+                libc::signal(signal, handler);
+            }
+        }
+    }
+
+    /// Forward the signals to `pid` from now on, and the one received while it was starting
+    pub fn forward_to(pid: u32) {
+        CHILD.store(pid as i32, Ordering::SeqCst);
+        let pending = PENDING.swap(0, Ordering::SeqCst);
+        if pending != 0 {
+            unsafe {
+                libc::kill(pid as i32, pending);
+            }
+        }
+    }
+
+    pub fn restore() {
+        for signal in FORWARDED {
+            unsafe {
+                libc::signal(signal, libc::SIG_DFL);
+            }
+        }
+        CHILD.store(0, Ordering::SeqCst);
+        PENDING.store(0, Ordering::SeqCst);
+    }
+}
@@ -1,14 +1,76 @@
-use log::trace;
+use log::{debug, trace, warn};
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use std::fs::{File, OpenOptions};
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tempfile::NamedTempFile;
 
-use crate::storage_backend::StorageBackend;
+use crate::storage_backend::{CacheEntry, DigestedReader, StorageBackend, StorageWriter};
+
+/// Suffix of the sidecar file storing the metadata of a cache next to it
+const METADATA_SUFFIX: &str = ".json";
+
+/// Suffix of the file whose modification time is the last access to the cache next to it.
+/// Kept out of the metadata, a read rewriting it could put back the digest of a replaced cache.
+const ACCESS_SUFFIX: &str = ".access";
+
+/// Lock file of the store, held exclusively while a cache and its digest are replaced
+const LOCK_FILE: &str = ".lock";
+
+/// Directory, relative to the base path, where corrupt caches are moved
+const QUARANTINE_DIR: &str = "quarantine";
+
+/// Suffix of the files being written, renamed once complete
+const TEMP_SUFFIX: &str = ".tmp";
+
+/// Age after which a temporary file is considered left by a killed push
+const STALE_TEMP_AGE: Duration = Duration::from_secs(24 * 60 * 60);
 
 fn hash_file_name(key: &str) -> String {
     let hash = Sha256::digest(key);
     base16ct::lower::encode_string(&hash)
 }
 
+/// Metadata stored next to each cache, as the file name is a hash of the key
+#[derive(Debug, Serialize, Deserialize)]
+struct Metadata {
+    key: String,
+    created: SystemTime,
+    /// Recorded on each read by older versions, see [`ACCESS_SUFFIX`]
+    #[serde(default)]
+    last_access: Option<SystemTime>,
+    /// Hex-encoded SHA-256 of the cache file
+    #[serde(default)]
+    sha256: Option<String>,
+}
+
+fn read_metadata(path: &Path) -> std::io::Result<Metadata> {
+    let file = File::open(path)?;
+    serde_json::from_reader(file).map_err(std::io::Error::other)
+}
+
+/// Temporary file next to `path`, with a unique name: runners in containers often share
+/// the store with the same PID, two of them writing the same key must not share a file
+fn temp_file_for(path: &Path) -> std::io::Result<NamedTempFile> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    tempfile::Builder::new()
+        .prefix(&format!(".{}.", name))
+        .suffix(TEMP_SUFFIX)
+        .tempfile_in(path.parent().unwrap_or(Path::new(".")))
+}
+
+fn write_metadata(path: &Path, metadata: &Metadata) -> std::io::Result<()> {
+    let mut file = temp_file_for(path)?;
+    serde_json::to_writer(&mut file, metadata)?;
+    file.as_file().sync_all()?;
+    file.persist(path)?;
+    Ok(())
+}
+
 pub struct FolderBackend {
     base_path: std::path::PathBuf,
 }
@@ -17,41 +79,491 @@ impl FolderBackend {
     pub fn new(base_path: std::path::PathBuf) -> Self {
         Self { base_path }
     }
+
+    fn metadata_path(&self, name: &str) -> PathBuf {
+        self.base_path.join(format!("{}{}", name, METADATA_SUFFIX))
+    }
+
+    fn access_path(&self, name: &str) -> PathBuf {
+        self.base_path.join(format!("{}{}", name, ACCESS_SUFFIX))
+    }
+
+    /// File access times are not reliable with `noatime` mounts, the time is set explicitly
+    fn record_access(&self, name: &str) -> std::io::Result<()> {
+        let file = File::options()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(self.access_path(name))?;
+        file.set_modified(SystemTime::now())
+    }
+
+    fn last_access(&self, name: &str) -> std::io::Result<Option<SystemTime>> {
+        match std::fs::metadata(self.access_path(name)) {
+            Ok(metadata) => Ok(Some(metadata.modified()?)),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Lock of the whole store, shared by the readers looking up a cache and its digest
+    fn lock_store(&self, exclusive: bool) -> std::io::Result<File> {
+        let path = self.base_path.join(LOCK_FILE);
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == ErrorKind::NotFound => File::options()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(&path)?,
+            Err(err) => return Err(err),
+        };
+        if exclusive {
+            file.lock()?;
+        } else {
+            file.lock_shared()?;
+        }
+        Ok(file)
+    }
+
+    /// Remove the temporary files of pushes that were killed before finishing,
+    /// they are not listed and would never be pruned
+    fn remove_stale_temp_files(&self) -> std::io::Result<()> {
+        let now = SystemTime::now();
+        for dir_entry in std::fs::read_dir(&self.base_path)? {
+            let dir_entry = dir_entry?;
+            if !dir_entry
+                .file_name()
+                .to_str()
+                .is_some_and(|name| name.ends_with(TEMP_SUFFIX))
+            {
+                continue;
+            }
+            let modified = dir_entry.metadata()?.modified()?;
+            if now.duration_since(modified).unwrap_or_default() < STALE_TEMP_AGE {
+                // Probably a push in progress
+                continue;
+            }
+            debug!("Removing stale temporary file {:?}", dir_entry.path());
+            match std::fs::remove_file(dir_entry.path()) {
+                Err(err) if err.kind() != ErrorKind::NotFound => return Err(err),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
 }
 
 impl StorageBackend for FolderBackend {
     type Error = std::io::Error;
-    fn reader(&self, key: &str) -> Result<impl std::io::Read, Self::Error> {
-        let name = hash_file_name(key);
-        let path = self.base_path.join(name);
-        let file = File::open(path)?;
+    fn reader<'s>(&'s self, key: &str) -> Result<impl std::io::Read + use<'s>, Self::Error> {
+        let file = self.untracked_reader(key)?;
+        if let Err(err) = self.record_access(&hash_file_name(key)) {
+            warn!("Could not record access time of cache {}: {}", key, err);
+        }
+        Ok(file)
+    }
+    fn untracked_reader<'s>(
+        &'s self,
+        key: &str,
+    ) -> Result<impl std::io::Read + use<'s>, Self::Error> {
+        let file = File::open(self.base_path.join(hash_file_name(key)))?;
         file.lock_shared()?;
         Ok(file)
     }
-    fn writer(&self, key: &str) -> Result<impl std::io::Write, Self::Error> {
+    fn reader_with_digest<'s>(
+        &'s self,
+        key: &str,
+        record_access: bool,
+    ) -> Result<DigestedReader<'s>, Self::Error> {
         let name = hash_file_name(key);
-        let path = self.base_path.join(name);
+        // A push replaces the cache then its digest under the exclusive lock
+        // Without the lock file in a read-only store, nothing pushes to it from here
+        let lock = self
+            .lock_store(false)
+            .inspect_err(|err| debug!("Reading cache {} without lock: {}", key, err))
+            .ok();
+        let file = self.untracked_reader(key)?;
+        let digest = self.digest(key)?;
+        drop(lock);
+        if record_access && let Err(err) = self.record_access(&name) {
+            warn!("Could not record access time of cache {}: {}", key, err);
+        }
+        Ok((Box::new(file), digest))
+    }
+    fn writer<'s>(&'s self, key: &str) -> Result<impl StorageWriter + use<'s>, Self::Error> {
+        let name = hash_file_name(key);
+        let path = self.base_path.join(&name);
         trace!("Writing to path {:?}", path);
         if let Some(parent) = path.parent() {
             trace!("Creating parent directory {:?}", parent);
             std::fs::create_dir_all(parent)?;
         }
+        if let Err(err) = self.remove_stale_temp_files() {
+            warn!("Could not remove stale temporary files: {}", err);
+        }
 
-        let file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(false)
-            .open(&path)?;
-        // Get an exclusive lock to make sure no reads are happening
-        file.lock()?;
-        // Truncate the file
-        file.set_len(0)?;
+        // Write to a temporary file in the same directory and rename it once complete,
+        // readers never see a partially written cache.
+        let file = temp_file_for(&path)?;
+        trace!("Writing to temporary path {:?}", file.path());
 
-        Ok(Box::new(file))
+        Ok(FolderWriter {
+            backend: self,
+            name,
+            file: Some(file),
+            key: key.to_string(),
+            hasher: Sha256::new(),
+        })
     }
     fn exists(&self, key: &str) -> Result<bool, Self::Error> {
         let name = hash_file_name(key);
         let path = self.base_path.join(name);
         Ok(path.exists())
     }
+    fn find_first(&self, keys: &[String]) -> Result<Option<usize>, Self::Error> {
+        // A single scan of the directory instead of a lookup per key
+        let dir = match std::fs::read_dir(&self.base_path) {
+            Ok(dir) => dir,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        let mut names = HashSet::new();
+        for dir_entry in dir {
+            names.insert(dir_entry?.file_name());
+        }
+        Ok(keys
+            .iter()
+            .position(|key| names.contains(OsStr::new(&hash_file_name(key)))))
+    }
+    fn list(&self, prefix: &str) -> Result<Vec<CacheEntry>, Self::Error> {
+        let dir = match std::fs::read_dir(&self.base_path) {
+            Ok(dir) => dir,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err),
+        };
+
+        // Caches written before metadata was stored have no sidecar file and are not listed
+        let mut entries = Vec::new();
+        for dir_entry in dir {
+            let dir_entry = dir_entry?;
+            let file_name = dir_entry.file_name();
+            let Some(name) = file_name
+                .to_str()
+                .and_then(|n| n.strip_suffix(METADATA_SUFFIX))
+            else {
+                continue;
+            };
+
+            let metadata = match read_metadata(&dir_entry.path()) {
+                Ok(metadata) => metadata,
+                Err(err) => {
+                    warn!("Ignoring invalid metadata file {:?}: {}", file_name, err);
+                    continue;
+                }
+            };
+            if !metadata.key.starts_with(prefix) {
+                continue;
+            }
+
+            let data = match std::fs::metadata(self.base_path.join(name)) {
+                Ok(data) => data,
+                Err(err) if err.kind() == ErrorKind::NotFound => {
+                    debug!("Metadata file {:?} has no cache next to it", file_name);
+                    continue;
+                }
+                Err(err) => return Err(err),
+            };
+
+            entries.push(CacheEntry {
+                key: metadata.key,
+                size: data.len(),
+                created: metadata.created,
+                last_access: self.last_access(name)?.or(metadata.last_access),
+            });
+        }
+
+        Ok(entries)
+    }
+    fn delete(&self, key: &str) -> Result<(), Self::Error> {
+        let name = hash_file_name(key);
+        let path = self.base_path.join(&name);
+        trace!("Deleting {:?}", path);
+        let file = File::open(&path)?;
+        // Wait for readers holding a shared lock to finish before removing the file
+        file.lock()?;
+        std::fs::remove_file(&path)?;
+        drop(file);
+        for path in [self.metadata_path(&name), self.access_path(&name)] {
+            match std::fs::remove_file(path) {
+                Err(err) if err.kind() != ErrorKind::NotFound => return Err(err),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+    fn digest(&self, key: &str) -> Result<Option<String>, Self::Error> {
+        let name = hash_file_name(key);
+        match read_metadata(&self.metadata_path(&name)) {
+            Ok(metadata) => Ok(metadata.sha256),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+    fn quarantine(&self, key: &str) -> Result<(), Self::Error> {
+        let name = hash_file_name(key);
+        let path = self.base_path.join(&name);
+        let quarantine_path = self.base_path.join(QUARANTINE_DIR);
+        std::fs::create_dir_all(&quarantine_path)?;
+        trace!("Moving {:?} to {:?}", path, quarantine_path);
+
+        let file = File::open(&path)?;
+        // Same as delete, wait for the readers to finish
+        file.lock()?;
+        std::fs::rename(&path, quarantine_path.join(&name))?;
+        drop(file);
+
+        for suffix in [METADATA_SUFFIX, ACCESS_SUFFIX] {
+            let sidecar_name = format!("{}{}", name, suffix);
+            match std::fs::rename(
+                self.base_path.join(&sidecar_name),
+                quarantine_path.join(sidecar_name),
+            ) {
+                Err(err) if err.kind() != ErrorKind::NotFound => return Err(err),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct FolderWriter<'s> {
+    backend: &'s FolderBackend,
+    /// File name of the cache in the store
+    name: String,
+    /// Removed when dropped before `finish`
+    file: Option<NamedTempFile>,
+    key: String,
+    hasher: Sha256,
+}
+
+impl Write for FolderWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.temp_file()?.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.temp_file()?.flush()
+    }
+}
+
+impl StorageWriter for FolderWriter<'_> {
+    fn finish(&mut self) -> std::io::Result<()> {
+        let file = self.file.take().ok_or_else(finished_error)?;
+        file.as_file().sync_all()?;
+        let path = self.backend.base_path.join(&self.name);
+
+        // Readers look up the cache and its digest under a shared lock, they get both old or both new
+        let _lock = self.backend.lock_store(true)?;
+        trace!("Renaming {:?} to {:?}", file.path(), path);
+        // Readers holding the previous file keep reading it, the rename only replaces the directory entry
+        file.persist(&path)?;
+
+        // The new cache was not used yet
+        match std::fs::remove_file(self.backend.access_path(&self.name)) {
+            Err(err) if err.kind() != ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+        write_metadata(
+            &self.backend.metadata_path(&self.name),
+            &Metadata {
+                key: self.key.clone(),
+                created: SystemTime::now(),
+                last_access: None,
+                sha256: Some(base16ct::lower::encode_string(
+                    &self.hasher.clone().finalize(),
+                )),
+            },
+        )?;
+        Ok(())
+    }
+}
+
+impl FolderWriter<'_> {
+    fn temp_file(&mut self) -> std::io::Result<&mut NamedTempFile> {
+        self.file.as_mut().ok_or_else(finished_error)
+    }
+}
+
+fn finished_error() -> std::io::Error {
+    std::io::Error::other("the cache was already stored")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::integrity::{self, Verification};
+    use std::io::Read;
+
+    #[test]
+    fn writer_removes_stale_temporary_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let backend = FolderBackend::new(dir.path().to_path_buf());
+        let stale = dir.path().join(".0123.42.tmp");
+        let recent = dir.path().join(".4567.43.tmp");
+        std::fs::write(&stale, b"partial").unwrap();
+        std::fs::write(&recent, b"partial").unwrap();
+        let old = SystemTime::now() - STALE_TEMP_AGE - Duration::from_secs(60);
+        filetime::set_file_mtime(&stale, filetime::FileTime::from_system_time(old)).unwrap();
+
+        let mut writer = backend.writer("build-abc").unwrap();
+        writer.write_all(b"cache").unwrap();
+        writer.finish().unwrap();
+
+        assert!(!stale.exists());
+        assert!(recent.exists());
+        assert!(backend.exists("build-abc").unwrap());
+    }
+
+    #[test]
+    fn concurrent_writers_of_a_key_do_not_share_a_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let backend = FolderBackend::new(dir.path().to_path_buf());
+        let mut first = backend.writer("build-abc").unwrap();
+        let mut second = backend.writer("build-abc").unwrap();
+        first.write_all(&[b'a'; 1000]).unwrap();
+        second.write_all(&[b'b'; 10]).unwrap();
+        first.write_all(&[b'a'; 1000]).unwrap();
+        second.finish().unwrap();
+        first.finish().unwrap();
+
+        let mut stored = Vec::new();
+        StorageBackend::reader(&backend, "build-abc")
+            .unwrap()
+            .read_to_end(&mut stored)
+            .unwrap();
+        assert_eq!(stored, [b'a'; 2000]);
+        assert_eq!(
+            integrity::verify_stored(&backend, "build-abc").unwrap(),
+            Verification::Valid
+        );
+    }
+
+    /// Store `data` under `key` and flip a byte of the stored file
+    fn store_corrupt(backend: &FolderBackend, key: &str, data: &[u8]) -> PathBuf {
+        let mut writer = backend.writer(key).unwrap();
+        writer.write_all(data).unwrap();
+        writer.finish().unwrap();
+        let path = backend.base_path.join(hash_file_name(key));
+        let mut stored = std::fs::read(&path).unwrap();
+        stored[1] ^= 0x01;
+        std::fs::write(&path, stored).unwrap();
+        path
+    }
+
+    #[test]
+    fn corrupt_cache_is_not_restored() {
+        let dir = tempfile::tempdir().unwrap();
+        let backend = FolderBackend::new(dir.path().to_path_buf());
+        store_corrupt(&backend, "build-abc", b"cache data");
+
+        let expected = StorageBackend::digest(&backend, "build-abc")
+            .unwrap()
+            .unwrap();
+        let reader = StorageBackend::reader(&backend, "build-abc").unwrap();
+        let err = integrity::verified_copy(reader, &expected).unwrap_err();
+        assert!(err.to_string().contains("corrupt"), "{}", err);
+    }
+
+    fn store(backend: &FolderBackend, key: &str, data: &[u8]) {
+        let mut writer = backend.writer(key).unwrap();
+        writer.write_all(data).unwrap();
+        writer.finish().unwrap();
+    }
+
+    #[test]
+    fn reading_does_not_rewrite_the_digest() {
+        let dir = tempfile::tempdir().unwrap();
+        let backend = FolderBackend::new(dir.path().to_path_buf());
+        store(&backend, "build-abc", b"cache data");
+        let metadata_path = backend.metadata_path(&hash_file_name("build-abc"));
+        let metadata = std::fs::read(&metadata_path).unwrap();
+
+        let (_, digest) = StorageBackend::reader_with_digest(&backend, "build-abc", true).unwrap();
+
+        assert_eq!(std::fs::read(&metadata_path).unwrap(), metadata);
+        assert_eq!(
+            digest,
+            StorageBackend::digest(&backend, "build-abc").unwrap()
+        );
+        let entries = StorageBackend::list(&backend, "").unwrap();
+        assert!(entries[0].last_access.is_some());
+
+        // A new push of the key was not used yet
+        store(&backend, "build-abc", b"new cache data");
+        let entries = StorageBackend::list(&backend, "").unwrap();
+        assert!(entries[0].last_access.is_none());
+    }
+
+    #[test]
+    fn reader_keeps_the_digest_of_the_data_it_reads() {
+        let dir = tempfile::tempdir().unwrap();
+        let backend = FolderBackend::new(dir.path().to_path_buf());
+        store(&backend, "build-abc", b"old cache data");
+        let (old_reader, old_digest) =
+            StorageBackend::reader_with_digest(&backend, "build-abc", true).unwrap();
+
+        store(&backend, "build-abc", b"new cache data");
+
+        integrity::verified_copy(old_reader, &old_digest.unwrap()).unwrap();
+        let (reader, digest) =
+            StorageBackend::reader_with_digest(&backend, "build-abc", true).unwrap();
+        let mut data = Vec::new();
+        integrity::verified_copy(reader, &digest.unwrap())
+            .unwrap()
+            .read_to_end(&mut data)
+            .unwrap();
+        assert_eq!(data, b"new cache data");
+    }
+
+    #[test]
+    fn verify_flags_corrupt_cache_without_recording_access() {
+        let dir = tempfile::tempdir().unwrap();
+        let backend = FolderBackend::new(dir.path().to_path_buf());
+        store_corrupt(&backend, "build-abc", b"cache data");
+        let mut writer = backend.writer("build-def").unwrap();
+        writer.write_all(b"valid data").unwrap();
+        writer.finish().unwrap();
+
+        assert!(matches!(
+            integrity::verify_stored(&backend, "build-abc").unwrap(),
+            Verification::Corrupt { .. }
+        ));
+        assert_eq!(
+            integrity::verify_stored(&backend, "build-def").unwrap(),
+            Verification::Valid
+        );
+        let entries = StorageBackend::list(&backend, "build-").unwrap();
+        assert!(entries.iter().all(|entry| entry.last_access.is_none()));
+    }
+
+    #[test]
+    fn quarantine_moves_corrupt_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let backend = FolderBackend::new(dir.path().to_path_buf());
+        let path = store_corrupt(&backend, "build-abc", b"cache data");
+
+        StorageBackend::quarantine(&backend, "build-abc").unwrap();
+
+        assert!(!StorageBackend::exists(&backend, "build-abc").unwrap());
+        assert!(StorageBackend::list(&backend, "").unwrap().is_empty());
+        let quarantined = dir
+            .path()
+            .join(QUARANTINE_DIR)
+            .join(path.file_name().unwrap());
+        assert!(quarantined.exists());
+        let metadata = read_metadata(&quarantined.with_extension("json")).unwrap();
+        assert_eq!(metadata.key, "build-abc");
+    }
 }
@@ -0,0 +1,57 @@
+use anyhow::{Result, bail};
+use gix::Repository;
+use gix::bstr::ByteSlice;
+use gix::glob::wildmatch;
+use log::{debug, trace};
+use sha2::{Digest, Sha256};
+
+/// Name of the cache key part replacing the commit when the key is derived from files
+pub const KEY_PART_PREFIX: &str = "files-";
+
+/// Digest of the content of the tracked files matching `patterns`, like `hashFiles` in GitHub Actions.
+///
+/// Patterns are matched against the paths relative to the root of the repository,
+/// `*` does not match `/` and `**/` matches any number of directories.
+/// The content is read from the working tree, uncommitted changes are taken into account.
+pub fn hash_files(repository: &Repository, patterns: &[String]) -> Result<String> {
+    let Some(workdir) = repository.workdir() else {
+        bail!("Can't hash files in a bare repository");
+    };
+    let index = repository.index_or_empty()?;
+
+    // Index entries are sorted by path, the digest does not depend on the order of the patterns
+    let mut hasher = Sha256::new();
+    let mut matched = 0;
+    for entry in index.entries() {
+        let path = entry.path(&index);
+        if !patterns.iter().any(|pattern| {
+            wildmatch(
+                pattern.as_bytes().as_bstr(),
+                path,
+                wildmatch::Mode::NO_MATCH_SLASH_LITERAL,
+            )
+        }) {
+            continue;
+        }
+
+        let file_path = workdir.join(gix::path::from_bstr(path));
+        let content = match std::fs::read(&file_path) {
+            Ok(content) => content,
+            // Deleted in the working tree but not committed yet
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err.into()),
+        };
+        trace!("Hashing {}", path);
+        hasher.update(path);
+        hasher.update([0]);
+        hasher.update(Sha256::digest(&content));
+        matched += 1;
+    }
+
+    if matched == 0 {
+        bail!("No tracked file matches {}", patterns.join(", "));
+    }
+    let digest = base16ct::lower::encode_string(&hasher.finalize());
+    debug!("Hashed {} files, digest {}", matched, digest);
+    Ok(digest)
+}
@@ -0,0 +1,444 @@
+use log::{debug, trace};
+use sha2::{Digest, Sha256};
+use std::io::{self, Read, Write};
+use std::sync::mpsc::{Receiver, SyncSender, sync_channel};
+use std::thread::JoinHandle;
+use ureq::http::Response;
+use ureq::typestate::WithBody;
+use ureq::{RequestBuilder, SendBody};
+
+use crate::storage_backend::{
+    CacheEntry, DIGEST_SUFFIX, StorageBackend, StorageWriter, find_first_concurrent,
+};
+
+/// Backend storing caches on a plain HTTP server supporting `GET`, `PUT` and `HEAD`
+/// (nginx with WebDAV, a bazel remote cache, ...).
+///
+/// If `CACHE_THING_HTTP_TOKEN` is set, it is sent as a bearer token.
+pub struct HttpBackend {
+    agent: ureq::Agent,
+    base_url: String,
+    token: Option<String>,
+}
+
+impl HttpBackend {
+    pub fn new(base_url: &str) -> Self {
+        let config = ureq::Agent::config_builder()
+            .http_status_as_error(false)
+            .build();
+        debug!("Using HTTP storage at {}", base_url);
+        Self {
+            agent: config.into(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            token: std::env::var("CACHE_THING_HTTP_TOKEN").ok(),
+        }
+    }
+
+    fn url(&self, key: &str) -> String {
+        format!("{}/{}", self.base_url, key)
+    }
+
+    fn authorization(&self) -> Option<String> {
+        self.token.as_ref().map(|token| format!("Bearer {}", token))
+    }
+}
+
+impl StorageBackend for HttpBackend {
+    type Error = io::Error;
+    fn reader<'s>(&'s self, key: &str) -> Result<impl Read + use<'s>, Self::Error> {
+        let mut request = self.agent.get(self.url(key));
+        if let Some(authorization) = self.authorization() {
+            request = request.header("authorization", authorization);
+        }
+        let response = check_status(request.call().map_err(io::Error::other)?)?;
+        Ok(response.into_body().into_reader())
+    }
+    fn writer<'s>(&'s self, key: &str) -> Result<impl StorageWriter + use<'s>, Self::Error> {
+        let url = self.url(key);
+        trace!("Uploading to {}", url);
+
+        let mut digest_request = self.agent.put(format!("{}{}", url, DIGEST_SUFFIX));
+        if let Some(authorization) = self.authorization() {
+            digest_request = digest_request.header("authorization", authorization);
+        }
+
+        let (sender, receiver) = sync_channel(16);
+        let mut request = self.agent.put(url);
+        if let Some(authorization) = self.authorization() {
+            request = request.header("authorization", authorization);
+        }
+        let upload = std::thread::spawn(move || {
+            let reader = ChannelReader {
+                receiver,
+                chunk: Vec::new(),
+                position: 0,
+            };
+            let response = request
+                .send(SendBody::from_owned_reader(reader))
+                .map_err(io::Error::other)?;
+            check_status(response)?;
+            Ok(())
+        });
+
+        Ok(HttpWriter {
+            sender: Some(sender),
+            upload: Some(upload),
+            hasher: Sha256::new(),
+            digest_request: Some(digest_request),
+        })
+    }
+    fn exists(&self, key: &str) -> Result<bool, Self::Error> {
+        let mut request = self.agent.head(self.url(key));
+        if let Some(authorization) = self.authorization() {
+            request = request.header("authorization", authorization);
+        }
+        let response = request.call().map_err(io::Error::other)?;
+        if response.status() == 404 {
+            return Ok(false);
+        }
+        check_status(response)?;
+        Ok(true)
+    }
+    fn find_first(&self, keys: &[String]) -> Result<Option<usize>, Self::Error> {
+        find_first_concurrent(keys, |key| self.exists(key))
+    }
+    fn list(&self, _prefix: &str) -> Result<Vec<CacheEntry>, Self::Error> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "the HTTP backend cannot list stored caches",
+        ))
+    }
+    fn delete(&self, key: &str) -> Result<(), Self::Error> {
+        for url in [self.url(key), format!("{}{}", self.url(key), DIGEST_SUFFIX)] {
+            let mut request = self.agent.delete(url);
+            if let Some(authorization) = self.authorization() {
+                request = request.header("authorization", authorization);
+            }
+            let response = request.call().map_err(io::Error::other)?;
+            // Caches stored by older versions have no digest
+            if response.status() != 404 {
+                check_status(response)?;
+            }
+        }
+        Ok(())
+    }
+    fn digest(&self, key: &str) -> Result<Option<String>, Self::Error> {
+        let mut request = self
+            .agent
+            .get(format!("{}{}", self.url(key), DIGEST_SUFFIX));
+        if let Some(authorization) = self.authorization() {
+            request = request.header("authorization", authorization);
+        }
+        let response = request.call().map_err(io::Error::other)?;
+        if response.status() == 404 {
+            return Ok(None);
+        }
+        let digest = check_status(response)?
+            .into_body()
+            .read_to_string()
+            .map_err(io::Error::other)?;
+        Ok(Some(digest.trim().to_string()))
+    }
+    fn store_digest(&self, key: &str, digest: &str) -> Result<(), Self::Error> {
+        let mut request = self
+            .agent
+            .put(format!("{}{}", self.url(key), DIGEST_SUFFIX));
+        if let Some(authorization) = self.authorization() {
+            request = request.header("authorization", authorization);
+        }
+        check_status(request.send(digest).map_err(io::Error::other)?)?;
+        Ok(())
+    }
+}
+
+enum Chunk {
+    Data(Vec<u8>),
+    /// Makes the upload fail so the server does not store a partial file
+    Abort,
+}
+
+/// Streams the written data to a `PUT` request running in a separate thread.
+pub struct HttpWriter {
+    sender: Option<SyncSender<Chunk>>,
+    upload: Option<JoinHandle<io::Result<()>>>,
+    hasher: Sha256,
+    /// Stores the digest of the cache once the upload completed
+    digest_request: Option<RequestBuilder<WithBody>>,
+}
+
+impl HttpWriter {
+    fn wait_upload(&mut self) -> io::Result<()> {
+        match self.upload.take() {
+            Some(upload) => upload
+                .join()
+                .map_err(|_| io::Error::other("upload thread panicked"))?,
+            None => Err(io::Error::other("upload already finished")),
+        }
+    }
+}
+
+impl Write for HttpWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let Some(sender) = &self.sender else {
+            return Err(io::Error::other("upload already finished"));
+        };
+        self.hasher.update(buf);
+        if sender.send(Chunk::Data(buf.to_vec())).is_err() {
+            // The request ended early, report its error
+            self.sender = None;
+            self.wait_upload()?;
+            return Err(io::Error::other("upload ended before all data was sent"));
+        }
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl StorageWriter for HttpWriter {
+    fn finish(&mut self) -> io::Result<()> {
+        // Closing the channel ends the request body
+        self.sender = None;
+        self.wait_upload()?;
+
+        let digest = base16ct::lower::encode_string(&self.hasher.clone().finalize());
+        let Some(digest_request) = self.digest_request.take() else {
+            return Err(io::Error::other("upload already finished"));
+        };
+        check_status(digest_request.send(digest).map_err(io::Error::other)?)?;
+        Ok(())
+    }
+}
+
+impl Drop for HttpWriter {
+    fn drop(&mut self) {
+        if let Some(sender) = self.sender.take() {
+            debug!("Aborting unfinished upload");
+            let _ = sender.send(Chunk::Abort);
+            drop(sender);
+            let _ = self.wait_upload();
+        }
+    }
+}
+
+struct ChannelReader {
+    receiver: Receiver<Chunk>,
+    chunk: Vec<u8>,
+    position: usize,
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position >= self.chunk.len() {
+            match self.receiver.recv() {
+                Ok(Chunk::Data(data)) => {
+                    self.chunk = data;
+                    self.position = 0;
+                }
+                Ok(Chunk::Abort) => return Err(io::Error::other("upload aborted")),
+                // The writer was closed, end of the body
+                Err(_) => return Ok(0),
+            }
+        }
+        let size = buf.len().min(self.chunk.len() - self.position);
+        buf[..size].copy_from_slice(&self.chunk[self.position..self.position + size]);
+        self.position += size;
+        Ok(size)
+    }
+}
+
+fn check_status(response: Response<ureq::Body>) -> io::Result<Response<ureq::Body>> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let kind = match status.as_u16() {
+        404 => io::ErrorKind::NotFound,
+        401 | 403 => io::ErrorKind::PermissionDenied,
+        _ => io::ErrorKind::Other,
+    };
+    Err(io::Error::new(
+        kind,
+        format!("HTTP request failed with status {}", status),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::io::{BufRead, BufReader};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::{Arc, Mutex};
+
+    type Objects = Arc<Mutex<HashMap<String, Vec<u8>>>>;
+
+    /// Serves the objects over HTTP, one connection at a time, and rejects the uploads under `denied/`
+    fn serve() -> (HttpBackend, Objects) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/caches", listener.local_addr().unwrap());
+        let objects = Objects::default();
+        let stored = objects.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                // Aborted uploads end with an error
+                let _ = handle(stream.unwrap(), &stored);
+            }
+        });
+        (HttpBackend::new(&url), objects)
+    }
+
+    fn handle(mut stream: TcpStream, objects: &Objects) -> io::Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let mut request = line.split_whitespace();
+        let method = request.next().unwrap_or_default().to_string();
+        let key = request
+            .next()
+            .and_then(|path| path.strip_prefix("/caches/"))
+            .unwrap_or_default()
+            .to_string();
+        let mut length = 0;
+        let mut chunked = false;
+        loop {
+            line.clear();
+            reader.read_line(&mut line)?;
+            let Some((name, value)) = line.trim_end().split_once(':') else {
+                break;
+            };
+            match name.to_ascii_lowercase().as_str() {
+                "content-length" => length = value.trim().parse().map_err(io::Error::other)?,
+                "transfer-encoding" => chunked = value.trim() == "chunked",
+                _ => {}
+            }
+        }
+
+        let (status, body) = match method.as_str() {
+            "PUT" if key.starts_with("denied/") => ("403 Forbidden", Vec::new()),
+            "PUT" => {
+                let body = if chunked {
+                    read_chunked(&mut reader)?
+                } else {
+                    let mut body = vec![0; length];
+                    reader.read_exact(&mut body)?;
+                    body
+                };
+                objects.lock().unwrap().insert(key, body);
+                ("201 Created", Vec::new())
+            }
+            _ => match objects.lock().unwrap().get(&key) {
+                Some(data) => ("200 OK", data.clone()),
+                None => ("404 Not Found", Vec::new()),
+            },
+        };
+        write!(
+            stream,
+            "HTTP/1.1 {}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n",
+            status,
+            body.len()
+        )?;
+        if method != "HEAD" {
+            stream.write_all(&body)?;
+        }
+        Ok(())
+    }
+
+    fn read_chunked(reader: &mut impl BufRead) -> io::Result<Vec<u8>> {
+        let mut body = Vec::new();
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if reader.read_line(&mut line)? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            let size = usize::from_str_radix(line.trim_end(), 16).map_err(io::Error::other)?;
+            // The chunk is followed by a line break
+            let mut chunk = vec![0; size + 2];
+            reader.read_exact(&mut chunk)?;
+            if size == 0 {
+                return Ok(body);
+            }
+            body.extend_from_slice(&chunk[..size]);
+        }
+    }
+
+    #[test]
+    fn uploads_caches_and_their_digest() {
+        let (backend, _) = serve();
+        let data: Vec<u8> = (0..200_000u32).map(|n| (n % 251) as u8).collect();
+        let mut writer = backend.writer("build-a").unwrap();
+        for chunk in data.chunks(1000) {
+            writer.write_all(chunk).unwrap();
+        }
+        writer.finish().unwrap();
+        drop(writer);
+
+        let mut read = Vec::new();
+        backend
+            .reader("build-a")
+            .unwrap()
+            .read_to_end(&mut read)
+            .unwrap();
+        assert_eq!(read, data);
+        assert_eq!(
+            backend.digest("build-a").unwrap(),
+            Some(base16ct::lower::encode_string(&Sha256::digest(&data)))
+        );
+        assert!(!backend.exists("build-b").unwrap());
+    }
+
+    #[test]
+    fn aborts_unfinished_uploads() {
+        let (backend, objects) = serve();
+        let mut writer = backend.writer("build-a").unwrap();
+        writer.write_all(b"partial").unwrap();
+        drop(writer);
+
+        assert!(!backend.exists("build-a").unwrap());
+        assert!(objects.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn reports_rejected_uploads() {
+        let (backend, objects) = serve();
+        let mut writer = backend.writer("denied/build-a").unwrap();
+        let chunk = vec![0; 64 * 1024];
+        // The upload ends once the server answers, the writes fail when the channel is full
+        let result = (0..1000)
+            .try_for_each(|_| writer.write_all(&chunk))
+            .and_then(|()| writer.finish());
+        assert!(result.is_err());
+        drop(writer);
+
+        assert!(objects.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn reads_the_channel_until_it_is_closed_or_aborted() {
+        let read = |chunks: Vec<Chunk>| {
+            let (sender, receiver) = sync_channel(chunks.len());
+            for chunk in chunks {
+                sender.send(chunk).unwrap();
+            }
+            drop(sender);
+            let mut reader = ChannelReader {
+                receiver,
+                chunk: Vec::new(),
+                position: 0,
+            };
+            let mut data = Vec::new();
+            reader.read_to_end(&mut data).map(|_| data)
+        };
+
+        let data = read(vec![
+            Chunk::Data(b"ab".to_vec()),
+            Chunk::Data(Vec::new()),
+            Chunk::Data(b"c".to_vec()),
+        ]);
+        assert_eq!(data.unwrap(), b"abc");
+        let aborted = read(vec![Chunk::Data(b"ab".to_vec()), Chunk::Abort]);
+        assert!(aborted.is_err());
+    }
+}
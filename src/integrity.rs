@@ -0,0 +1,74 @@
+use anyhow::{Result, bail};
+use log::debug;
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Seek, Write};
+
+use crate::storage_backend::DynStorageBackend;
+
+/// Outcome of checking a stored cache against the digest recorded by the writer
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verification {
+    Valid,
+    /// Stored by an older version, there is nothing to check against
+    Unverified,
+    Corrupt {
+        expected: String,
+        actual: String,
+    },
+}
+
+/// Copy `reader` to `output`, returning the hex-encoded SHA-256 and the size of the data.
+pub fn copy_with_digest(
+    reader: &mut impl Read,
+    output: &mut impl Write,
+) -> io::Result<(String, u64)> {
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut size = 0;
+    loop {
+        let read = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        hasher.update(&buffer[..read]);
+        output.write_all(&buffer[..read])?;
+        size += read as u64;
+    }
+    output.flush()?;
+    Ok((base16ct::lower::encode_string(&hasher.finalize()), size))
+}
+
+/// Download the archive to a temporary file and check its digest,
+/// so nothing is extracted from a corrupt archive.
+pub fn verified_copy(mut reader: impl Read, expected: &str) -> Result<File> {
+    let mut file = tempfile::tempfile()?;
+    let (digest, size) = copy_with_digest(&mut reader, &mut BufWriter::new(&mut file))?;
+    if digest != expected {
+        bail!(
+            "Cache is corrupt: expected SHA-256 {} but got {}",
+            expected,
+            digest
+        );
+    }
+    debug!("Verified SHA-256 of the cache ({} bytes)", size);
+    file.rewind()?;
+    Ok(file)
+}
+
+/// Read a stored cache and compare it to its recorded digest.
+/// The access is not recorded, checking a cache is not using it.
+pub fn verify_stored(backend: &dyn DynStorageBackend, key: &str) -> Result<Verification> {
+    let (mut reader, expected) = backend.reader_with_digest(key, false)?;
+    let Some(expected) = expected else {
+        return Ok(Verification::Unverified);
+    };
+    let (actual, _) = copy_with_digest(&mut reader, &mut io::sink())?;
+    if actual == expected {
+        Ok(Verification::Valid)
+    } else {
+        Ok(Verification::Corrupt { expected, actual })
+    }
+}
@@ -1,19 +1,53 @@
 use std::{
-    io::{BufReader, BufWriter, Read},
+    cell::Cell,
+    fs::OpenOptions,
+    io::{BufReader, BufWriter, Read, Write},
     path::{Path, PathBuf},
+    rc::Rc,
+    time::Instant,
 };
 
 use anyhow::{Result, bail};
-use clap::{Args, Parser, Subcommand};
-use flate2::{Compression, write::GzEncoder};
+use clap::parser::ValueSource;
+use clap::{
+    ArgGroup, ArgMatches, Args, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum,
+};
 use gix::{Commit, ObjectId, Repository, hashtable::hash_map::HashMap};
-use log::{debug, info, trace, warn};
+use log::{debug, error, info, trace, warn};
+use serde::Serialize;
 use sha2::{Digest, Sha256};
 
-use crate::storage_backend::StorageBackend;
+use crate::ci::CiContext;
+use crate::compression::Compression;
+use crate::config::{CacheConfig, Config};
+use crate::exclude::Exclusions;
+use crate::integrity::Verification;
+use crate::restore_state::{RestoreState, RestoredPath};
+use crate::scope::{ScopeArgs, ScopedBackend};
+use crate::storage_backend::{DynStorageBackend, StorageWriter};
+use crate::unpack::RestoreArgs;
 
+mod cache_key;
+mod ci;
+mod compression;
+mod config;
+mod exclude;
+mod exec;
 mod folder_backend;
+mod hash_files;
+mod http_backend;
+mod integrity;
+mod manifest;
+#[cfg(test)]
+mod memory_backend;
+mod pack;
+mod prune;
+mod restore_state;
+mod s3_backend;
+mod scope;
 pub mod storage_backend;
+mod tree_key;
+mod unpack;
 
 #[derive(Debug, Parser)]
 #[command(name = "cache-thing")]
@@ -27,16 +61,115 @@ struct Cli {
 enum Commands {
     Push(PushArgs),
     Pull(PullArgs),
+    List(ListArgs),
+    Prune(PruneArgs),
+    Delete(DeleteArgs),
+    Verify(VerifyArgs),
+    /// Pull the cache, run a command and push the cache if the command succeeded
+    Exec(ExecArgs),
+}
+
+#[derive(Debug, Clone, Args)]
+struct BackendArgs {
+    /// Where caches are stored, as a URL: file:///cache, s3://bucket/prefix, http://host/path.
+    /// A path without scheme uses the folder backend
+    #[arg(
+        long,
+        env = "CACHE_THING_LOCATION",
+        default_value = "/tmp/cache-thing/data"
+    )]
+    location: String,
+
+    /// Force the storage backend instead of deducing it from the location scheme
+    #[arg(long, value_enum)]
+    backend: Option<BackendKind>,
+}
+
+#[derive(Debug, Clone, Args)]
+struct GitArgs {
+    /// Reference of the default branch, for example origin/develop or upstream/main.
+    /// Can be repeated or comma-separated, the first existing reference is used.
+    /// Defaults to origin/HEAD, then origin/main and origin/master
+    #[arg(long, env = "CACHE_THING_BASE_REF", value_delimiter = ',')]
+    base_ref: Vec<String>,
+}
+
+/// How the history is searched for a cache to restore
+#[derive(Debug, Clone, Args)]
+struct SearchArgs {
+    /// Number of commits of the current branch to look for a cache.
+    /// With --tree-path it is the number of versions of the trees, commits that don't change them are not counted
+    #[arg(long, env = "CACHE_THING_SEARCH_DEPTH", default_value_t = 10)]
+    search_depth: usize,
+
+    /// Which commits are searched
+    #[arg(long, env = "CACHE_THING_SEARCH_STRATEGY", value_enum, default_value_t = SearchStrategy::Ancestors)]
+    search_strategy: SearchStrategy,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum SearchStrategy {
+    /// All the ancestors of the current commit, including the merged branches
+    Ancestors,
+    /// Only the first parent of each commit, skipping the merged branches
+    FirstParent,
+    /// Ancestors of the current commit, then the merge-base with the main branch and its ancestors
+    MergeBase,
+}
+
+#[derive(Debug, Clone, Args)]
+struct FallbackArgs {
+    /// Fallback key to use if no cache is found
+    /// For example pulling the cache of the nightly build.
+    /// Can be repeated, the keys are tried in order
+    #[arg(long)]
+    fallback_key: Vec<String>,
+
+    /// When the fallback keys are tried
+    #[arg(long, value_enum, default_value_t = FallbackPosition::BeforeMain)]
+    fallback_position: FallbackPosition,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum FallbackPosition {
+    /// Before the commits of the current branch
+    BeforeAncestors,
+    /// After the commits of the current branch, before the commit on the main branch
+    BeforeMain,
+    /// After the commit on the main branch
+    AfterMain,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum BackendKind {
+    Folder,
+    S3,
+    Http,
 }
 
 #[derive(Debug, Args)]
 struct PushArgs {
+    /// Name of a cache defined in cache-thing.toml, the arguments given override its definition
+    name: Option<String>,
+
     /// Files to push to cache storage
     #[arg(short, long)]
     files: Vec<String>,
 
+    /// Leave out the files matching this pattern, like target/**/incremental.
+    /// Matched against the paths below the pushed files as given with --files,
+    /// files with the cache-thing-exclude gitattribute are also left out
+    #[arg(long)]
+    exclude: Vec<String>,
+
     /// Name of the cache, to differentiate if multiple are stored in the same backend
-    #[arg(short, long)]
+    #[arg(
+        short,
+        long,
+        required_unless_present = "name",
+        default_value = "",
+        hide_default_value = true
+    )]
     prefix: String,
 
     /// Optional suffix to append to the cache key
@@ -46,26 +179,244 @@ struct PushArgs {
     /// Replace the commit hash with a fixed key
     #[arg(long)]
     fixed_key: Option<String>,
+
+    /// Replace the commit hash with a digest of the tracked files matching this glob, for example Cargo.lock.
+    /// Can be repeated, patterns are relative to the root of the repository
+    #[arg(long, conflicts_with = "fixed_key")]
+    hash_files: Vec<String>,
+
+    /// Replace the commit hash with the git tree of this path, commits that don't change it share the cache.
+    /// Can be repeated, paths are relative to the root of the repository
+    #[arg(long, conflicts_with_all = ["fixed_key", "hash_files"])]
+    tree_path: Vec<String>,
+
+    /// Don't push if a cache already exists with the key, for example when a job is retried
+    #[arg(long)]
+    skip_existing: bool,
+
+    /// Don't push if the files are identical to the cache restored by pull.
+    /// The digest of the files is recorded in the cache for the next pull
+    #[arg(long)]
+    skip_if_unchanged: bool,
+
+    /// Compression of the archive
+    #[arg(long, value_enum, default_value_t = Compression::Zstd)]
+    compression: Compression,
+
+    /// Compression level, defaults to 6 for gzip and 3 for zstd
+    #[arg(long, allow_negative_numbers = true)]
+    level: Option<i32>,
+
+    /// Archive the extended attributes of the files
+    #[arg(long)]
+    xattrs: bool,
+
+    /// Limits enforced on the caches of the same name after pushing
+    #[command(flatten)]
+    prune: prune::PruneLimits,
+
+    #[command(flatten)]
+    scope: ScopeArgs,
+
+    #[command(flatten)]
+    git: GitArgs,
+
+    #[command(flatten)]
+    backend: BackendArgs,
 }
 
 #[derive(Debug, Args)]
 struct PullArgs {
+    /// Name of a cache defined in cache-thing.toml, the arguments given override its definition
+    name: Option<String>,
+
     #[arg(short, long)]
     files: Vec<String>,
 
     /// Name of the cache, to differentiate if multiple are stored in the same backend
-    #[arg(short, long)]
+    #[arg(
+        short,
+        long,
+        required_unless_present = "name",
+        default_value = "",
+        hide_default_value = true
+    )]
     prefix: String,
 
     /// Optional suffix
     #[arg(short, long)]
     suffix: Option<String>,
 
-    /// Fallback key to use if no cache is found
-    /// For example pulling the cache of the nightly build
-    /// Fallback key will be checked befor the commit on the main branch
+    #[command(flatten)]
+    fallback: FallbackArgs,
+
+    /// Key the cache is pushed with by `exec --fixed-key`, tried before any other key
+    #[arg(skip)]
+    fixed_key: Option<String>,
+
+    /// Prefix of the keys to use if no cache is found for the commits, for example build-linux-.
+    /// The most recent cache whose key starts with it is restored. Can be repeated, tried in order.
+    /// Ignored with a warning by the backends that cannot list caches, like the HTTP backend
+    #[arg(long)]
+    restore_key: Vec<String>,
+
+    /// Look first for the cache pushed with the same --hash-files digest,
+    /// then for the caches of the previous commits
+    #[arg(long)]
+    hash_files: Vec<String>,
+
+    /// Look for the caches pushed with the same --tree-path,
+    /// using the previous versions of the trees instead of the previous commits
+    #[arg(long)]
+    tree_path: Vec<String>,
+
+    #[command(flatten)]
+    search: SearchArgs,
+
+    /// Exit with 0 instead of 4 when no cache is found
+    #[arg(long)]
+    allow_miss: bool,
+
+    /// Exit with 3 when the cache was restored from another key than the one of the current commit
+    #[arg(long)]
+    detailed_exitcode: bool,
+
+    /// Format of the result printed on the standard output
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    output: OutputFormat,
+
+    /// Restore the extended attributes archived with push --xattrs
+    #[arg(long)]
+    xattrs: bool,
+
+    #[command(flatten)]
+    restore: RestoreArgs,
+
+    #[command(flatten)]
+    scope: ScopeArgs,
+
+    #[command(flatten)]
+    git: GitArgs,
+
+    #[command(flatten)]
+    backend: BackendArgs,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    /// Only log messages
+    Text,
+    /// A JSON object describing the restored cache
+    Json,
+}
+
+/// Exit code of pull when no cache is found.
+/// Errors exit with 1 and invalid arguments with 2, a script accepting a miss must not accept them.
+const EXIT_MISS: i32 = 4;
+/// Exit code of pull with --detailed-exitcode when the cache of another commit was restored
+const EXIT_FALLBACK_HIT: i32 = 3;
+
+#[derive(Debug, Args)]
+struct ExecArgs {
+    #[command(flatten)]
+    push: PushArgs,
+
+    #[command(flatten)]
+    fallback: FallbackArgs,
+
+    /// Prefix of the keys to use if no cache is found for the commits, for example build-linux-
+    #[arg(long)]
+    restore_key: Vec<String>,
+
+    #[command(flatten)]
+    search: SearchArgs,
+
+    #[command(flatten)]
+    restore: RestoreArgs,
+
+    /// Push the cache even if the command failed
+    #[arg(long)]
+    push_on_failure: bool,
+
+    /// Command to run, after --
+    #[arg(last = true, required = true)]
+    command: Vec<String>,
+}
+
+#[derive(Debug, Args)]
+struct ListArgs {
+    /// Only list the caches with this name
+    #[arg(short, long)]
+    prefix: Option<String>,
+
+    #[command(flatten)]
+    scope: ScopeArgs,
+
+    #[command(flatten)]
+    backend: BackendArgs,
+}
+
+#[derive(Debug, Args)]
+struct PruneArgs {
+    /// Only prune the caches with this name, limits apply to all the caches otherwise
+    #[arg(short, long)]
+    prefix: Option<String>,
+
+    /// Only print the caches that would be evicted
+    #[arg(long)]
+    dry_run: bool,
+
+    #[command(flatten)]
+    limits: prune::PruneLimits,
+
+    #[command(flatten)]
+    scope: ScopeArgs,
+
+    #[command(flatten)]
+    backend: BackendArgs,
+}
+
+#[derive(Debug, Args)]
+#[command(group(ArgGroup::new("target").required(true).args(["key", "all_for_prefix"])))]
+struct DeleteArgs {
+    /// Name of the cache
+    #[arg(short, long)]
+    prefix: String,
+
+    /// Optional suffix of the cache key
+    #[arg(short, long)]
+    suffix: Option<String>,
+
+    /// Commit hash or fixed key of the cache to delete
+    #[arg(short, long)]
+    key: Option<String>,
+
+    /// Delete every cache with this name, and the caches pushed with a --fixed-key after it
+    #[arg(long)]
+    all_for_prefix: bool,
+
+    #[command(flatten)]
+    scope: ScopeArgs,
+
+    #[command(flatten)]
+    backend: BackendArgs,
+}
+
+#[derive(Debug, Args)]
+struct VerifyArgs {
+    /// Only verify the caches with this name
+    #[arg(short, long)]
+    prefix: Option<String>,
+
+    /// Move corrupt caches out of the way so they are not restored anymore
     #[arg(long)]
-    fallback_key: Option<String>,
+    quarantine: bool,
+
+    #[command(flatten)]
+    scope: ScopeArgs,
+
+    #[command(flatten)]
+    backend: BackendArgs,
 }
 
 fn main() {
@@ -82,73 +433,397 @@ fn main() {
 fn try_main() -> Result<i32> {
     env_logger::init();
 
-    let args = Cli::parse();
+    let matches = Cli::command().get_matches();
+    let mut args = Cli::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+    let Some((_, matches)) = matches.subcommand() else {
+        unreachable!("a subcommand is required");
+    };
+
+    match &mut args.command {
+        Commands::Push(push_args) => {
+            apply_push_config(push_args, matches)?;
+            push(push_args)
+        }
+        Commands::Pull(pull_args) => {
+            apply_pull_config(pull_args, matches)?;
+            pull(pull_args)
+        }
+        Commands::List(list_args) => list(list_args),
+        Commands::Prune(prune_args) => prune(prune_args),
+        Commands::Delete(delete_args) => delete(delete_args),
+        Commands::Verify(verify_args) => verify(verify_args),
+        Commands::Exec(exec_args) => {
+            apply_exec_config(exec_args, matches)?;
+            exec(exec_args)
+        }
+    }
+}
+
+/// The argument was given on the command line or in the environment, it overrides the configuration file
+fn is_explicit(matches: &ArgMatches, id: &str) -> bool {
+    matches!(
+        matches.value_source(id),
+        Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+    )
+}
+
+/// Replace `target` by the configured value, unless the argument is explicit
+fn configure<T>(matches: &ArgMatches, id: &str, target: &mut T, value: Option<T>) {
+    if let Some(value) = value
+        && !is_explicit(matches, id)
+    {
+        *target = value;
+    }
+}
+
+fn non_empty(values: &[String]) -> Option<Vec<String>> {
+    (!values.is_empty()).then(|| values.to_vec())
+}
+
+/// A key strategy given on the command line replaces the one of the configuration file
+fn has_explicit_key(matches: &ArgMatches) -> bool {
+    ["fixed_key", "hash_files", "tree_path"]
+        .iter()
+        .any(|id| matches.try_contains_id(id).is_ok() && is_explicit(matches, id))
+}
+
+fn apply_push_config(args: &mut PushArgs, matches: &ArgMatches) -> Result<()> {
+    let Some(name) = args.name.clone() else {
+        return Ok(());
+    };
+    let config = Config::load()?;
+    configure_push(args, &name, config.cache(&name)?, matches)
+}
 
-    match &args.command {
-        Commands::Push(push_args) => push(push_args),
-        Commands::Pull(pull_args) => pull(pull_args),
+fn configure_push(
+    args: &mut PushArgs,
+    name: &str,
+    cache: &CacheConfig,
+    matches: &ArgMatches,
+) -> Result<()> {
+    configure(
+        matches,
+        "prefix",
+        &mut args.prefix,
+        Some(cache.prefix.clone().unwrap_or_else(|| name.to_string())),
+    );
+    configure(matches, "files", &mut args.files, non_empty(&cache.paths));
+    configure(
+        matches,
+        "exclude",
+        &mut args.exclude,
+        non_empty(&cache.exclude),
+    );
+    configure(
+        matches,
+        "suffix",
+        &mut args.suffix,
+        cache.suffix.clone().map(Some),
+    );
+    if !has_explicit_key(matches) {
+        args.fixed_key = cache.fixed_key.clone();
+        args.hash_files = cache.hash_files.clone();
+        args.tree_path = cache.tree_paths.clone();
+    }
+    let compression = cache
+        .compression
+        .as_deref()
+        .map(|compression| Compression::from_str(compression, true))
+        .transpose()
+        .map_err(anyhow::Error::msg)?;
+    configure(matches, "compression", &mut args.compression, compression);
+    configure(matches, "level", &mut args.level, cache.level.map(Some));
+    apply_backend_config(&mut args.backend, cache, matches)
+}
+
+fn apply_pull_config(args: &mut PullArgs, matches: &ArgMatches) -> Result<()> {
+    let Some(name) = args.name.clone() else {
+        return Ok(());
+    };
+    let config = Config::load()?;
+    configure_pull(args, &name, config.cache(&name)?, matches)
+}
+
+fn configure_pull(
+    args: &mut PullArgs,
+    name: &str,
+    cache: &CacheConfig,
+    matches: &ArgMatches,
+) -> Result<()> {
+    configure(
+        matches,
+        "prefix",
+        &mut args.prefix,
+        Some(cache.prefix.clone().unwrap_or_else(|| name.to_string())),
+    );
+    configure(matches, "files", &mut args.files, non_empty(&cache.paths));
+    configure(
+        matches,
+        "suffix",
+        &mut args.suffix,
+        cache.suffix.clone().map(Some),
+    );
+    configure(
+        matches,
+        "fallback_key",
+        &mut args.fallback.fallback_key,
+        non_empty(&cache.fallback_keys),
+    );
+    configure(
+        matches,
+        "restore_key",
+        &mut args.restore_key,
+        non_empty(&cache.restore_keys),
+    );
+    if !has_explicit_key(matches) {
+        args.hash_files = cache.hash_files.clone();
+        args.tree_path = cache.tree_paths.clone();
+        // Push stores the cache under the fixed key
+        args.fixed_key = cache.fixed_key.clone();
     }
+    apply_backend_config(&mut args.backend, cache, matches)
+}
+
+fn apply_exec_config(args: &mut ExecArgs, matches: &ArgMatches) -> Result<()> {
+    let Some(name) = args.push.name.clone() else {
+        return Ok(());
+    };
+    let config = Config::load()?;
+    configure_exec(args, &name, config.cache(&name)?, matches)
+}
+
+fn configure_exec(
+    args: &mut ExecArgs,
+    name: &str,
+    cache: &CacheConfig,
+    matches: &ArgMatches,
+) -> Result<()> {
+    configure_push(&mut args.push, name, cache, matches)?;
+    configure(
+        matches,
+        "fallback_key",
+        &mut args.fallback.fallback_key,
+        non_empty(&cache.fallback_keys),
+    );
+    configure(
+        matches,
+        "restore_key",
+        &mut args.restore_key,
+        non_empty(&cache.restore_keys),
+    );
+    Ok(())
+}
+
+fn apply_backend_config(
+    args: &mut BackendArgs,
+    cache: &CacheConfig,
+    matches: &ArgMatches,
+) -> Result<()> {
+    configure(
+        matches,
+        "location",
+        &mut args.location,
+        cache.location.clone(),
+    );
+    let backend = cache
+        .backend
+        .as_deref()
+        .map(|backend| BackendKind::from_str(backend, true))
+        .transpose()
+        .map_err(anyhow::Error::msg)?;
+    configure(matches, "backend", &mut args.backend, backend.map(Some));
+    Ok(())
 }
 
 fn push(args: &PushArgs) -> Result<i32> {
-    let file_backend = get_backend();
+    let file_backend = scoped_backend(get_backend(&args.backend)?, &args.scope, true)?;
 
     let key = if let Some(fixed_key) = &args.fixed_key {
         format_cache_key_str(&args.prefix, fixed_key.clone(), args.suffix.clone())
     } else {
-        current_key(&args.prefix, args.suffix.clone())?
+        exact_key(
+            &args.prefix,
+            args.suffix.clone(),
+            &args.hash_files,
+            &args.tree_path,
+            &args.git.base_ref,
+        )?
     };
 
-    info!("Storing cache with key {}", &key);
+    if args.skip_existing && file_backend.exists(&key)? {
+        // Checked first, hashing the files can take longer than the lookup
+        info!("Cache with key {} already exists, not pushing", &key);
+    } else {
+        let repository = gix::discover(".").ok();
+        let mut exclusions = Exclusions::new(repository.as_ref(), &args.exclude)?;
+
+        let digests = if args.skip_if_unchanged {
+            args.files
+                .iter()
+                .map(|file| manifest::path_digest(Path::new(file), &mut exclusions).map(Some))
+                .collect::<std::io::Result<Vec<_>>>()?
+        } else {
+            vec![None; args.files.len()]
+        };
 
-    let writer = BufWriter::new(file_backend.writer(&key)?);
-    let encoder = GzEncoder::new(writer, Compression::default());
-    let mut archive = tar::Builder::new(encoder);
-    for file in &args.files {
-        let stat = std::fs::metadata(file)?;
-        let hash = hash_from_path(file);
-        if stat.is_dir() {
-            trace!("Adding directory {} to archive", file);
-            archive.append_dir_all(hash, file)?;
+        if let Some(restored_key) = unchanged_since_restore(args, &digests)? {
+            info!(
+                "Files are unchanged since cache {} was restored, not pushing",
+                restored_key
+            );
         } else {
-            trace!("Adding file {} to archive", file);
-            archive.append_path_with_name(file, hash)?;
+            store(file_backend.as_ref(), &key, args, digests, &mut exclusions)?;
         }
     }
 
-    archive.finish()?;
+    if args.prune.is_set()
+        && let Err(err) = prune::prune(
+            file_backend.as_ref(),
+            Some(&args.prefix),
+            &args.prune,
+            false,
+        )
+    {
+        warn!("Could not prune caches: {}", err);
+    }
 
-    info!("Cache stored with key {}", &key);
     Ok(0)
 }
 
 struct FileEntry {
     pub path: String,
-    pub extracted: bool,
+    pub restored_files: u64,
+    pub restored_size: u64,
 }
 
-fn pull(args: &PullArgs) -> Result<i32> {
-    let file_backend = get_backend();
-
-    let possible_keys =
-        possible_restore_keys(&args.prefix, args.suffix.clone(), args.fallback_key.clone())?;
-    let mut key = None;
-    for k in possible_keys {
-        trace!("Looking for cache with key {}", &k);
-        if file_backend.exists(&k)? {
-            debug!("Found cache with key {}", &k);
-            key = Some(k);
-            break;
+/// Archive the files of `args` under `key`
+fn store(
+    file_backend: &dyn DynStorageBackend,
+    key: &str,
+    args: &PushArgs,
+    digests: Vec<Option<String>>,
+    exclusions: &mut Exclusions,
+) -> Result<()> {
+    info!("Storing cache with key {}", key);
+
+    let writer = BufWriter::new(file_backend.writer(key)?);
+    let encoder = compression::Encoder::new(writer, args.compression, args.level)?;
+    let mut archive = tar::Builder::new(encoder);
+
+    let mut manifest = manifest::Manifest::new(head_commit_id());
+    for (file, digest) in args.files.iter().zip(digests) {
+        let entry = manifest.add_path(file, hash_from_path(file), digest, exclusions)?;
+        debug!(
+            "Path {} has {} files ({})",
+            file,
+            entry.files,
+            format_size(entry.size)
+        );
+    }
+    manifest.append_to(&mut archive)?;
+
+    for file in &args.files {
+        pack::append_path(
+            &mut archive,
+            Path::new(&hash_from_path(file)),
+            Path::new(file),
+            args.xattrs,
+            exclusions,
+        )?;
+    }
+
+    let encoder = archive.into_inner()?;
+    let mut writer = encoder.finish()?.into_inner().map_err(|e| e.into_error())?;
+    writer.finish()?;
+
+    info!("Cache stored with key {}", key);
+
+    Ok(())
+}
+
+/// Key of the restored cache if the files have the digests it recorded
+fn unchanged_since_restore(args: &PushArgs, digests: &[Option<String>]) -> Result<Option<String>> {
+    if !args.skip_if_unchanged {
+        return Ok(None);
+    }
+    let Some(state) = RestoreState::load(&args.prefix, args.suffix.as_deref())? else {
+        debug!("No cache was restored for {}", args.prefix);
+        return Ok(None);
+    };
+    for (file, digest) in args.files.iter().zip(digests) {
+        if state.digest(file) != digest.as_deref() {
+            debug!("{} changed since cache {} was restored", file, state.key);
+            return Ok(None);
         }
     }
+    Ok(Some(state.key))
+}
 
-    let key = if let Some(k) = key {
-        k
-    } else {
-        bail!("No cache found for prefix {}", &args.prefix);
+fn pull(args: &PullArgs) -> Result<i32> {
+    let file_backend = scoped_backend(get_backend(&args.backend)?, &args.scope, false)?;
+
+    let started = Instant::now();
+
+    // A failed pull must not leave the state of a previous one
+    RestoreState::clear(&args.prefix, args.suffix.as_deref())?;
+
+    let mut possible_keys = possible_restore_keys(
+        &args.prefix,
+        args.suffix.clone(),
+        &args.fallback,
+        &args.hash_files,
+        &args.tree_path,
+        &args.git.base_ref,
+        &args.search,
+    )?;
+    if let Some(fixed_key) = &args.fixed_key {
+        let fixed_keys = fallback_keys(&args.prefix, &args.suffix, std::slice::from_ref(fixed_key));
+        possible_keys.splice(0..0, fixed_keys);
+    }
+    for k in &possible_keys {
+        trace!("Looking for cache with key {}", k);
+    }
+    let mut report = PullReport {
+        candidates: possible_keys.len(),
+        ..Default::default()
+    };
+    let key = match file_backend.find_first(&possible_keys)? {
+        Some(index) => {
+            report.position = Some(index);
+            Some(possible_keys.swap_remove(index))
+        }
+        None => find_by_restore_keys(file_backend.as_ref(), &args.restore_key)?,
+    };
+    report.lookup_ms = started.elapsed().as_millis();
+
+    let Some(key) = key else {
+        if args.allow_miss {
+            info!("No cache found for prefix {}", &args.prefix);
+        } else {
+            error!("No cache found for prefix {}", &args.prefix);
+        }
+        report_pull(args, &report)?;
+        return Ok(if args.allow_miss { 0 } else { EXIT_MISS });
+    };
+    debug!("Found cache with key {}", &key);
+    report.hit = true;
+    // Without a key for the current commit, for example when the tree paths were removed, nothing is exact
+    report.exact = match &args.fixed_key {
+        Some(fixed_key) => {
+            format_cache_key_str(&args.prefix, fixed_key.clone(), args.suffix.clone()) == key
+        }
+        None => exact_key(
+            &args.prefix,
+            args.suffix.clone(),
+            &args.hash_files,
+            &args.tree_path,
+            &args.git.base_ref,
+        )
+        .is_ok_and(|exact| exact == key),
     };
+    report.key = Some(key.clone());
 
-    let mut file_etries: HashMap<String, FileEntry> = args
+    let mut file_entries: HashMap<String, FileEntry> = args
         .files
         .iter()
         .map(|f| {
@@ -157,34 +832,86 @@ fn pull(args: &PullArgs) -> Result<i32> {
                 hash.clone(),
                 FileEntry {
                     path: f.clone(),
-                    extracted: false,
+                    restored_files: 0,
+                    restored_size: 0,
                 },
             )
         })
         .collect();
 
-    let reader = BufReader::new(file_backend.reader(&key)?);
-    let decoder = flate2::read::GzDecoder::new(reader);
+    let bytes = Rc::new(Cell::new(0));
+    let (inner, digest) = file_backend.reader_with_digest(&key, true)?;
+    let backend_reader = CountingReader {
+        inner,
+        count: bytes.clone(),
+    };
+    let reader: Box<dyn Read> = match digest {
+        Some(expected) => Box::new(integrity::verified_copy(backend_reader, &expected)?),
+        None => {
+            warn!(
+                "Cache {} has no recorded digest, it can't be verified before extraction",
+                key
+            );
+            Box::new(backend_reader)
+        }
+    };
+    let decoder = compression::decoder(BufReader::new(reader))?;
     let mut archive = tar::Archive::new(decoder);
+    let mut unpacker = unpack::Unpacker::new(&args.restore, args.xattrs)?;
+    unpacker.configure(&mut archive);
+
+    // Archives created by older versions have no manifest
+    let mut manifest = None;
 
     for entry in archive.entries()? {
         let mut entry = entry?;
-        let path = entry.path()?;
-        let components = path.components().collect::<Vec<_>>();
-        let hash = components.first().unwrap().as_os_str().to_string_lossy();
+        let path = entry.path()?.into_owned();
 
-        if let Some(file_entry) = file_etries.get_mut(&hash.to_string()) {
-            let without_hash = components.iter().skip(1).collect::<PathBuf>();
-            let mut output_path = PathBuf::from(&file_entry.path);
-            output_path.push(&without_hash);
+        if path == Path::new(manifest::MANIFEST_PATH) {
+            let archive_manifest = manifest::Manifest::read_from(&mut entry)?;
+            info!(
+                "Cache created by cache-thing {} on {} from commit {}",
+                archive_manifest.version,
+                archive_manifest.host.as_deref().unwrap_or("unknown host"),
+                archive_manifest.commit.as_deref().unwrap_or("unknown")
+            );
+            for (hash, file_entry) in &file_entries {
+                if archive_manifest.find(hash).is_none() {
+                    bail!("Path {} is not in cache {}", file_entry.path, key);
+                }
+            }
+            manifest = Some(archive_manifest);
+            continue;
+        }
 
+        let Some(first) = path.components().next() else {
+            bail!("Cache {} has an entry without a name", key);
+        };
+        let hash = first.as_os_str().to_string_lossy();
+
+        if let Some(file_entry) = file_entries.get_mut(&hash.to_string()) {
             trace!(
                 "Extracting file {} to {}",
                 path.to_string_lossy(),
-                output_path.to_string_lossy()
+                file_entry.path
             );
-            entry.unpack(output_path)?;
-            file_entry.extracted = true;
+            let size = entry.header().size()?;
+            if let Err(err) =
+                unpacker.unpack_entry(&mut entry, Path::new(&file_entry.path), &hash, &path)
+            {
+                bail!(
+                    "Could not extract {} from cache {}: {}",
+                    path.to_string_lossy(),
+                    key,
+                    err
+                );
+            }
+            file_entry.restored_files += 1;
+            file_entry.restored_size += size;
+        } else if file_entries.values().all(|e| e.restored_files > 0) {
+            // The entries of a path are contiguous, nothing left to extract
+            debug!("All requested paths were restored, skipping the rest of the archive");
+            break;
         } else {
             trace!(
                 "Skipping file {} (not in requested files)",
@@ -192,33 +919,426 @@ fn pull(args: &PullArgs) -> Result<i32> {
             );
         }
     }
+    unpacker.finish()?;
 
-    for (_, file_entry) in file_etries.iter().filter(|(_, e)| !e.extracted) {
-        warn!("Path {} was asked but not found in cache", file_entry.path);
+    for (hash, file_entry) in &file_entries {
+        if file_entry.restored_files == 0 {
+            warn!("Path {} was asked but not found in cache", file_entry.path);
+            continue;
+        }
+        info!(
+            "Restored {} ({} files, {})",
+            file_entry.path,
+            file_entry.restored_files,
+            format_size(file_entry.restored_size)
+        );
+        if let Some(expected) = manifest.as_ref().and_then(|m| m.find(hash))
+            && expected.files != file_entry.restored_files
+        {
+            warn!(
+                "Path {} has {} files in the cache manifest but {} were restored",
+                file_entry.path, expected.files, file_entry.restored_files
+            );
+        }
+    }
+
+    if let Err(err) = record_restore(args, &key, manifest.as_ref(), &file_entries) {
+        warn!("Could not record the restored cache: {}", err);
+    }
+
+    report.bytes = bytes.get();
+    report.restore_ms = started.elapsed().as_millis() - report.lookup_ms;
+    report_pull(args, &report)?;
+
+    if !report.exact && args.detailed_exitcode {
+        return Ok(EXIT_FALLBACK_HIT);
+    }
+    Ok(0)
+}
+
+/// Result of a pull, printed with `--output json`
+#[derive(Debug, Default, Serialize)]
+struct PullReport {
+    hit: bool,
+    /// The cache of the current commit was restored, not the one of an ancestor or a fallback
+    exact: bool,
+    key: Option<String>,
+    /// Position of the key in the candidates, not set when found with a restore key
+    position: Option<usize>,
+    candidates: usize,
+    /// Size of the archive read from the storage
+    bytes: u64,
+    lookup_ms: u128,
+    restore_ms: u128,
+}
+
+fn report_pull(args: &PullArgs, report: &PullReport) -> Result<()> {
+    if args.output == OutputFormat::Json {
+        println!("{}", serde_json::to_string(report)?);
+    }
+
+    // Step outputs of GitHub Actions, cache-hit has the same meaning as in actions/cache
+    if let Some(path) = std::env::var_os("GITHUB_OUTPUT").filter(|path| !path.is_empty()) {
+        let mut file = OpenOptions::new().append(true).create(true).open(path)?;
+        writeln!(file, "cache-hit={}", report.exact)?;
+        if let Some(key) = &report.key {
+            writeln!(file, "cache-matched-key={}", key)?;
+        }
+    }
+    Ok(())
+}
+
+/// Counts the bytes read from the storage backend
+struct CountingReader<R> {
+    inner: R,
+    count: Rc<Cell<u64>>,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.count.set(self.count.get() + read as u64);
+        Ok(read)
+    }
+}
+
+/// Remember the digests of the restored paths for `push --skip-if-unchanged`
+fn record_restore(
+    args: &PullArgs,
+    key: &str,
+    manifest: Option<&manifest::Manifest>,
+    file_entries: &HashMap<String, FileEntry>,
+) -> Result<()> {
+    let mut paths = Vec::new();
+    for (hash, file_entry) in file_entries {
+        let digest = manifest
+            .and_then(|manifest| manifest.find(hash))
+            .and_then(|entry| entry.digest.clone());
+        match digest {
+            Some(digest) if file_entry.restored_files > 0 => paths.push(RestoredPath {
+                path: file_entry.path.clone(),
+                digest,
+            }),
+            // The cache was pushed without --skip-if-unchanged, nothing to compare with
+            _ => return Ok(()),
+        }
+    }
+
+    let state = RestoreState {
+        key: key.to_string(),
+        paths,
+    };
+    state.save(&args.prefix, args.suffix.as_deref())
+}
+
+/// Most recent cache whose key starts with one of `restore_keys`, tried in order
+fn find_by_restore_keys(
+    backend: &dyn DynStorageBackend,
+    restore_keys: &[String],
+) -> Result<Option<String>> {
+    for restore_key in restore_keys {
+        trace!("Looking for cache with key prefix {}", restore_key);
+        let entries = match backend.list(restore_key) {
+            Ok(entries) => entries,
+            Err(err)
+                if err
+                    .downcast_ref::<std::io::Error>()
+                    .is_some_and(|err| err.kind() == std::io::ErrorKind::Unsupported) =>
+            {
+                warn!("Ignoring --restore-key: {}", err);
+                return Ok(None);
+            }
+            Err(err) => return Err(err),
+        };
+        let newest = entries.into_iter().max_by_key(|entry| entry.created);
+        if let Some(entry) = newest {
+            return Ok(Some(entry.key));
+        }
+    }
+    Ok(None)
+}
+
+fn exec(args: &ExecArgs) -> Result<i32> {
+    let pull_args = PullArgs {
+        name: args.push.name.clone(),
+        files: args.push.files.clone(),
+        prefix: args.push.prefix.clone(),
+        suffix: args.push.suffix.clone(),
+        fallback: args.fallback.clone(),
+        // The fixed key is what the previous runs pushed
+        fixed_key: args.push.fixed_key.clone(),
+        restore_key: args.restore_key.clone(),
+        hash_files: args.push.hash_files.clone(),
+        tree_path: args.push.tree_path.clone(),
+        search: args.search.clone(),
+        allow_miss: true,
+        detailed_exitcode: false,
+        output: OutputFormat::Text,
+        xattrs: args.push.xattrs,
+        restore: args.restore.clone(),
+        scope: args.push.scope.clone(),
+        git: args.push.git.clone(),
+        backend: args.push.backend.clone(),
+    };
+    // A missing cache only makes the command slower
+    if let Err(err) = pull(&pull_args) {
+        warn!("Could not restore the cache: {}", err);
+    }
+
+    let exit_code = exec::run(&args.command)?;
+    if exit_code != 0 && !args.push_on_failure {
+        info!("Command failed with exit code {}, not pushing", exit_code);
+        return Ok(exit_code);
+    }
+
+    if let Err(err) = push(&args.push) {
+        warn!("Could not store the cache: {}", err);
+    }
+    Ok(exit_code)
+}
+
+fn list(args: &ListArgs) -> Result<i32> {
+    let file_backend = scoped_backend(get_backend(&args.backend)?, &args.scope, false)?;
+
+    let mut entries = cache_key::list_caches(file_backend.as_ref(), args.prefix.as_deref())?;
+    entries.sort_by_key(|entry| std::cmp::Reverse(entry.created));
+
+    println!(
+        "{:<64}  {:>10}  {:<20}  {:<20}",
+        "KEY", "SIZE", "CREATED", "LAST ACCESS"
+    );
+    for entry in entries {
+        let last_access = entry
+            .last_access
+            .map(|t| humantime::format_rfc3339_seconds(t).to_string())
+            .unwrap_or("-".to_string());
+        println!(
+            "{:<64}  {:>10}  {:<20}  {:<20}",
+            entry.key,
+            format_size(entry.size),
+            humantime::format_rfc3339_seconds(entry.created).to_string(),
+            last_access
+        );
+    }
+
+    Ok(0)
+}
+
+fn prune(args: &PruneArgs) -> Result<i32> {
+    let file_backend = scoped_backend(get_backend(&args.backend)?, &args.scope, true)?;
+
+    if !args.limits.is_set() {
+        bail!("No limit set, use --max-size, --max-age or --max-entries");
+    }
+
+    let evicted = prune::prune(
+        file_backend.as_ref(),
+        args.prefix.as_deref(),
+        &args.limits,
+        args.dry_run,
+    )?;
+
+    let freed = evicted.iter().map(|entry| entry.size).sum();
+    for entry in &evicted {
+        println!("{}", entry.key);
+    }
+    info!(
+        "{} {} caches ({})",
+        if args.dry_run {
+            "Would evict"
+        } else {
+            "Evicted"
+        },
+        evicted.len(),
+        format_size(freed)
+    );
+
+    Ok(0)
+}
+
+fn delete(args: &DeleteArgs) -> Result<i32> {
+    let file_backend = scoped_backend(get_backend(&args.backend)?, &args.scope, true)?;
+
+    let keys = if let Some(key) = &args.key {
+        let key = format_cache_key_str(&args.prefix, key.clone(), args.suffix.clone());
+        if !file_backend.exists(&key)? {
+            bail!("No cache found with key {}", key);
+        }
+        vec![key]
+    } else {
+        cache_key::list_caches(file_backend.as_ref(), Some(&args.prefix))?
+            .into_iter()
+            .map(|entry| entry.key)
+            .collect()
+    };
+
+    for key in &keys {
+        file_backend.delete(key)?;
+        info!("Deleted cache {}", key);
+    }
+    if keys.is_empty() {
+        warn!("No cache found for prefix {}", args.prefix);
     }
 
     Ok(0)
 }
 
-fn get_backend() -> impl StorageBackend {
-    // TODO: storage backend selection
+fn verify(args: &VerifyArgs) -> Result<i32> {
+    // Only the caches of the own scope can be moved to quarantine
+    let file_backend = scoped_backend(get_backend(&args.backend)?, &args.scope, args.quarantine)?;
+
+    let mut entries = cache_key::list_caches(file_backend.as_ref(), args.prefix.as_deref())?;
+    entries.sort_by(|a, b| a.key.cmp(&b.key));
 
-    let location =
-        std::env::var("CACHE_THING_LOCATION").unwrap_or("/tmp/cache-thing/data".to_string());
+    let mut corrupt = 0;
+    for entry in entries {
+        let (expected, actual) = match integrity::verify_stored(file_backend.as_ref(), &entry.key)?
+        {
+            Verification::Valid => {
+                println!("OK         {}", entry.key);
+                continue;
+            }
+            Verification::Unverified => {
+                println!("UNVERIFIED {}", entry.key);
+                continue;
+            }
+            Verification::Corrupt { expected, actual } => (expected, actual),
+        };
+
+        corrupt += 1;
+        println!("CORRUPT    {}", entry.key);
+        debug!("Expected SHA-256 {} but got {}", expected, actual);
+        if args.quarantine {
+            file_backend.quarantine(&entry.key)?;
+            info!("Moved cache {} to quarantine", entry.key);
+        }
+    }
+
+    if corrupt > 0 {
+        warn!("Found {} corrupt caches", corrupt);
+        return Ok(1);
+    }
+    Ok(0)
+}
 
-    folder_backend::FolderBackend::new(std::path::PathBuf::from(location))
+fn format_size(size: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = size as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", size, UNITS[0])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
 }
 
-fn current_key(prefix: &str, suffix: Option<String>) -> Result<String> {
+/// Restrict the backend to the scopes of the build when scoping is enabled.
+/// With `own_scope_only`, the caches of the other scopes are not visible, pushing, deleting
+/// and pruning only check and remove the caches of the own scope.
+fn scoped_backend(
+    backend: Box<dyn DynStorageBackend>,
+    args: &ScopeArgs,
+    own_scope_only: bool,
+) -> Result<Box<dyn DynStorageBackend>> {
+    if !args.scoped {
+        return Ok(backend);
+    }
+    let repository = gix::discover(".")?;
+    let Some(mut scopes) = args.scopes(&repository, &CiContext::detect())? else {
+        return Ok(backend);
+    };
+    if own_scope_only {
+        scopes.readable = vec![scopes.own.clone()];
+    }
+    Ok(Box::new(ScopedBackend::new(backend, scopes)))
+}
+
+fn get_backend(args: &BackendArgs) -> Result<Box<dyn DynStorageBackend>> {
+    Ok(match storage_location(args)? {
+        StorageLocation::Folder(path) => Box::new(folder_backend::FolderBackend::new(path)),
+        StorageLocation::S3 { bucket, prefix } => {
+            Box::new(s3_backend::S3Backend::new(bucket, prefix)?)
+        }
+        StorageLocation::Http(url) => Box::new(http_backend::HttpBackend::new(&url)),
+    })
+}
+
+/// Where the caches are stored, as given by `--location` and `--backend`
+#[derive(Debug, PartialEq, Eq)]
+enum StorageLocation<'a> {
+    Folder(PathBuf),
+    S3 { bucket: &'a str, prefix: &'a str },
+    Http(String),
+}
+
+fn storage_location(args: &BackendArgs) -> Result<StorageLocation<'_>> {
+    let (scheme, path) = match args.location.split_once("://") {
+        Some((scheme, path)) => (Some(scheme), path),
+        None => (None, args.location.as_str()),
+    };
+
+    let kind = match (args.backend, scheme) {
+        (Some(kind), _) => kind,
+        (None, None | Some("file")) => BackendKind::Folder,
+        (None, Some("s3")) => BackendKind::S3,
+        (None, Some("http" | "https")) => BackendKind::Http,
+        (None, Some(scheme)) => bail!("Unsupported storage location scheme '{}'", scheme),
+    };
+    debug!("Using {:?} backend with location {}", kind, args.location);
+
+    Ok(match kind {
+        BackendKind::Folder => StorageLocation::Folder(PathBuf::from(path)),
+        BackendKind::S3 => {
+            let (bucket, prefix) = path.split_once('/').unwrap_or((path, ""));
+            StorageLocation::S3 { bucket, prefix }
+        }
+        BackendKind::Http => StorageLocation::Http(match scheme {
+            Some(_) => args.location.clone(),
+            None => format!("http://{}", path),
+        }),
+    })
+}
+
+/// Key pushed for the current commit when no fixed key is given
+fn exact_key(
+    prefix: &str,
+    suffix: Option<String>,
+    hash_files: &[String],
+    tree_paths: &[String],
+    base_refs: &[String],
+) -> Result<String> {
+    if !hash_files.is_empty() {
+        let repository = gix::discover(".")?;
+        let digest = hash_files::hash_files(&repository, hash_files)?;
+        return Ok(format_cache_key_str(
+            prefix,
+            format!("{}{}", hash_files::KEY_PART_PREFIX, digest),
+            suffix,
+        ));
+    }
+    current_key(prefix, suffix, tree_paths, base_refs)
+}
+
+fn current_key(
+    prefix: &str,
+    suffix: Option<String>,
+    tree_paths: &[String],
+    base_refs: &[String],
+) -> Result<String> {
     let repository = gix::discover(".")?;
     let head = repository.head_commit()?;
     let mut head_id = head.id;
 
-    let main_commit = main_commit(&repository)?;
+    let ci = CiContext::detect();
+    let main_commit = main_commit(&repository, base_refs, &ci)?;
 
     // If we're in a merge/pull request, the head is a merge commit between main and the feature branch.
     // We want to find the parent that is not main to use as the cache key.
-    if in_merge_request_ci() {
+    if ci.merge_request {
         let parents = head.parent_ids().collect::<Vec<_>>();
         if parents.len() > 1 {
             for parent in &parents {
@@ -231,11 +1351,70 @@ fn current_key(prefix: &str, suffix: Option<String>) -> Result<String> {
         }
     }
 
-    Ok(format_cache_key(prefix, head_id, suffix))
+    let Some(key_part) = commit_key_part(&repository, head_id, tree_paths)? else {
+        bail!(
+            "None of the tree paths {} exist at commit {}",
+            tree_paths.join(", "),
+            head_id
+        );
+    };
+    Ok(format_cache_key_str(prefix, key_part, suffix))
+}
+
+/// Part of the cache key identifying `commit`, the trees of `tree_paths` if set.
+/// Returns `None` if none of the tree paths exist at this commit.
+fn commit_key_part(
+    repository: &Repository,
+    commit: ObjectId,
+    tree_paths: &[String],
+) -> Result<Option<String>> {
+    if tree_paths.is_empty() {
+        return Ok(Some(commit.to_string()));
+    }
+    tree_key::tree_key(repository, commit, tree_paths)
+}
+
+/// Most commits walked looking for the previous versions of the tree paths
+const TREE_SEARCH_MAX_COMMITS: usize = 1000;
+
+/// Add the first `depth` of `walk` to `commits` with their key part.
+/// With tree paths it is `depth` versions of the trees, the commits that don't change them are skipped:
+/// the walk goes on until the trees changed `depth` times or after [`TREE_SEARCH_MAX_COMMITS`].
+fn search_commits(
+    repository: &Repository,
+    walk: impl Iterator<Item = Result<ObjectId>>,
+    depth: usize,
+    tree_paths: &[String],
+    commits: &mut Vec<(ObjectId, String)>,
+) -> Result<()> {
+    let limit = if tree_paths.is_empty() {
+        depth
+    } else {
+        TREE_SEARCH_MAX_COMMITS.max(depth)
+    };
+    let mut versions = 0;
+    for commit in walk.take(limit) {
+        if versions == depth {
+            break;
+        }
+        let commit = commit?;
+        let Some(key_part) = commit_key_part(repository, commit, tree_paths)? else {
+            continue;
+        };
+        if commits.iter().any(|(_, known)| *known == key_part) {
+            continue;
+        }
+        versions += 1;
+        commits.push((commit, key_part));
+    }
+    Ok(())
 }
 
-fn format_cache_key(prefix: &str, commit: ObjectId, suffix: Option<String>) -> String {
-    format_cache_key_str(prefix, commit.to_string(), suffix)
+/// Commit checked out in the current repository, if any
+fn head_commit_id() -> Option<String> {
+    let repository = gix::discover(".").ok()?;
+    let head_id = repository.head_id().ok()?;
+    Some(head_id.to_string())
 }
 
 fn format_cache_key_str(prefix: &str, key: String, suffix: Option<String>) -> String {
@@ -249,11 +1428,35 @@ fn format_cache_key_str(prefix: &str, key: String, suffix: Option<String>) -> St
 fn possible_restore_keys(
     prefix: &str,
     suffix: Option<String>,
-    fallback_key: Option<String>,
+    fallback: &FallbackArgs,
+    hash_files: &[String],
+    tree_paths: &[String],
+    base_refs: &[String],
+    search: &SearchArgs,
 ) -> Result<Vec<String>> {
     let repository = gix::discover(".")?;
 
-    let main_commit = main_commit(&repository)?;
+    let mut keys = Vec::new();
+
+    // the files did not change since the cache was pushed, it's the closest match
+    if !hash_files.is_empty() {
+        let digest = hash_files::hash_files(&repository, hash_files)?;
+        let files_key = format!("{}{}", hash_files::KEY_PART_PREFIX, digest);
+        if suffix.is_some() {
+            keys.push(format_cache_key_str(
+                prefix,
+                files_key.clone(),
+                suffix.clone(),
+            ));
+        }
+        keys.push(format_cache_key_str(prefix, files_key, None));
+    }
+
+    if fallback.fallback_position == FallbackPosition::BeforeAncestors {
+        keys.extend(fallback_keys(prefix, &suffix, &fallback.fallback_key));
+    }
+
+    let main_commit = main_commit(&repository, base_refs, &CiContext::detect())?;
 
     let head = repository.head_commit()?;
     trace!("Current HEAD is at commit {}", head.id);
@@ -269,20 +1472,52 @@ fn possible_restore_keys(
 
     trace!("HEAD parents: {:?}", head_parents);
 
-    // look for cache in the last 10 commits in the current branch.
-    // if we are on main we look at the last 10 commits of main.
+    // look for cache in the last commits of the current branch.
+    // if we are on main we look at the last commits of main.
     let parent_commits = head.ancestors();
-    let parrent_commits = if head.id == main_commit.id {
+    let parent_commits = if search.search_strategy == SearchStrategy::FirstParent {
+        parent_commits.first_parent_only()
+    } else {
+        parent_commits
+    };
+    let parent_commits = if head.id == main_commit.id {
         parent_commits
     } else {
         parent_commits.with_boundary([main_commit.id])
     };
 
-    let parent_commits_list = parrent_commits.all()?.take(10);
+    let mut commits = Vec::new();
+    search_commits(
+        &repository,
+        parent_commits.all()?.map(|element| Ok(element?.id)),
+        search.search_depth,
+        tree_paths,
+        &mut commits,
+    )?;
 
-    let mut keys = Vec::new();
-    for element in parent_commits_list {
-        let commit = element?.id;
+    if search.search_strategy == SearchStrategy::MergeBase && head.id != main_commit.id {
+        // the branch forked from main before its current commit,
+        // caches pushed on main around that point are closer than the current main commit
+        match repository.merge_base(head.id, main_commit.id) {
+            Ok(merge_base) => {
+                trace!("Merge-base with the main branch is {}", merge_base);
+                search_commits(
+                    &repository,
+                    merge_base
+                        .ancestors()
+                        .first_parent_only()
+                        .all()?
+                        .map(|element| Ok(element?.id)),
+                    search.search_depth,
+                    tree_paths,
+                    &mut commits,
+                )?;
+            }
+            Err(err) => debug!("No merge-base with the main branch: {}", err),
+        }
+    }
+
+    for (commit, key_part) in commits {
         trace!("Considering commit {:?}", commit);
 
         if commit == main_commit.id {
@@ -290,13 +1525,41 @@ fn possible_restore_keys(
             continue;
         }
 
+        // with tree paths, unrelated commits share the same key
+        let key = format_cache_key_str(prefix, key_part.clone(), None);
+        if keys.contains(&key) {
+            continue;
+        }
         if suffix.is_some() {
-            keys.push(format_cache_key(prefix, commit, suffix.clone()));
+            keys.push(format_cache_key_str(prefix, key_part, suffix.clone()));
         }
-        keys.push(format_cache_key(prefix, commit, None));
+        keys.push(key);
+    }
+
+    if fallback.fallback_position == FallbackPosition::BeforeMain {
+        keys.extend(fallback_keys(prefix, &suffix, &fallback.fallback_key));
     }
 
-    if let Some(fallback_key) = fallback_key {
+    if let Some(key_part) = commit_key_part(&repository, main_commit.id, tree_paths)? {
+        let key = format_cache_key_str(prefix, key_part.clone(), None);
+        if !keys.contains(&key) {
+            if suffix.is_some() {
+                keys.push(format_cache_key_str(prefix, key_part, suffix.clone()));
+            }
+            keys.push(key);
+        }
+    }
+
+    if fallback.fallback_position == FallbackPosition::AfterMain {
+        keys.extend(fallback_keys(prefix, &suffix, &fallback.fallback_key));
+    }
+    Ok(keys)
+}
+
+/// Keys of the fallbacks in order, each with the suffix first if set
+fn fallback_keys(prefix: &str, suffix: &Option<String>, fallback_keys: &[String]) -> Vec<String> {
+    let mut keys = Vec::new();
+    for fallback_key in fallback_keys {
         if suffix.is_some() {
             keys.push(format_cache_key_str(
                 prefix,
@@ -304,43 +1567,49 @@ fn possible_restore_keys(
                 suffix.clone(),
             ));
         }
-        keys.push(format_cache_key_str(prefix, fallback_key, None));
+        keys.push(format_cache_key_str(prefix, fallback_key.clone(), None));
     }
-
-    if suffix.is_some() {
-        keys.push(format_cache_key(prefix, main_commit.id, suffix));
-    }
-    keys.push(format_cache_key(prefix, main_commit.id, None));
-    Ok(keys)
+    keys
 }
 
-fn in_merge_request_ci() -> bool {
-    if let Ok(var) = std::env::var("GITHUB_REF")
-        && var.contains("refs/pull/")
-    {
-        true
+/// References tried, in order, when no base reference is given.
+/// origin/HEAD points to the default branch of the remote when it was cloned.
+const DEFAULT_BASE_REFS: [&str; 3] = ["origin/HEAD", "origin/main", "origin/master"];
+
+fn main_commit<'r>(
+    repository: &'r Repository,
+    base_refs: &[String],
+    ci: &CiContext,
+) -> Result<Commit<'r>> {
+    let mut candidates = Vec::new();
+    if base_refs.is_empty() {
+        // In a merge request, the target branch is what the branch will be merged into
+        if let Some(target_branch) = &ci.target_branch {
+            candidates.push(format!("origin/{}", target_branch));
+        }
+        candidates.extend(DEFAULT_BASE_REFS.iter().map(|r| r.to_string()));
     } else {
-        false
+        candidates.extend(base_refs.iter().cloned());
     }
-}
 
-fn main_commit(repository: &'_ Repository) -> Result<Commit<'_>> {
-    // TODO: ability to set a different default branch
-    let main_ref = repository.try_find_reference("origin/main")?;
-    let mut main_ref = if let Some(r) = main_ref {
-        r
-    } else {
-        let master_ref = repository.try_find_reference("origin/master")?;
-        if let Some(r) = master_ref {
-            r
-        } else {
-            bail!("Could not find 'origin/main' or 'origin/master' reference");
+    for candidate in &candidates {
+        if let Some(mut reference) = repository.try_find_reference(candidate.as_str())? {
+            let main_commit = reference.peel_to_commit()?;
+            trace!(
+                "Main branch {} ({}) is at commit {}",
+                candidate,
+                reference.name().as_bstr(),
+                main_commit.id
+            );
+            return Ok(main_commit);
         }
-    };
-    let main_commit = main_ref.peel_to_commit()?;
-    trace!("Main branch is at commit {}", main_commit.id);
+        trace!("Reference {} not found", candidate);
+    }
 
-    Ok(main_commit)
+    bail!(
+        "Could not find any of the base references: {}",
+        candidates.join(", ")
+    );
 }
 
 fn hash_from_path<P>(path: P) -> String
@@ -350,3 +1619,223 @@ where
     let hash = Sha256::digest(path.as_ref().to_string_lossy().as_bytes());
     base16ct::lower::encode_string(&hash)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parse the command line, returning the arguments of the subcommand
+    fn parse(args: &[&str]) -> (Commands, ArgMatches) {
+        let matches = Cli::command()
+            .try_get_matches_from(["cache-thing"].iter().chain(args))
+            .unwrap();
+        let cli = Cli::from_arg_matches(&matches).unwrap();
+        let (_, matches) = matches.subcommand().unwrap();
+        (cli.command, matches.clone())
+    }
+
+    fn build_cache() -> CacheConfig {
+        CacheConfig {
+            paths: vec!["target".to_string()],
+            exclude: vec!["target/**/incremental".to_string()],
+            hash_files: vec!["Cargo.lock".to_string()],
+            compression: Some("gzip".to_string()),
+            level: Some(9),
+            location: Some("s3://bucket/caches".to_string()),
+            fallback_keys: vec!["nightly".to_string()],
+            restore_keys: vec!["build-".to_string()],
+            ..CacheConfig::default()
+        }
+    }
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    #[test]
+    fn push_arguments_override_the_configuration() {
+        let (Commands::Push(mut args), matches) = parse(&["push", "build"]) else {
+            unreachable!();
+        };
+        configure_push(&mut args, "build", &build_cache(), &matches).unwrap();
+        assert_eq!(args.prefix, "build");
+        assert_eq!(args.files, strings(&["target"]));
+        assert_eq!(args.exclude, strings(&["target/**/incremental"]));
+        assert_eq!(args.hash_files, strings(&["Cargo.lock"]));
+        assert_eq!(args.compression, Compression::Gzip);
+        assert_eq!(args.level, Some(9));
+        assert_eq!(args.backend.location, "s3://bucket/caches");
+
+        let (Commands::Push(mut args), matches) = parse(&[
+            "push",
+            "build",
+            "-p",
+            "release",
+            "-f",
+            "dist",
+            "--compression",
+            "zstd",
+            "--location",
+            "/caches",
+            "--fixed-key",
+            "nightly",
+        ]) else {
+            unreachable!();
+        };
+        configure_push(&mut args, "build", &build_cache(), &matches).unwrap();
+        assert_eq!(args.prefix, "release");
+        assert_eq!(args.files, strings(&["dist"]));
+        assert_eq!(args.exclude, strings(&["target/**/incremental"]));
+        assert_eq!(args.compression, Compression::Zstd);
+        assert_eq!(args.level, Some(9));
+        assert_eq!(args.backend.location, "/caches");
+        // The key strategy of the command line replaces the one of the file
+        assert_eq!(args.fixed_key.as_deref(), Some("nightly"));
+        assert!(args.hash_files.is_empty());
+    }
+
+    #[test]
+    fn pull_arguments_override_the_configuration() {
+        let cache = CacheConfig {
+            prefix: Some("build-linux".to_string()),
+            fixed_key: Some("nightly".to_string()),
+            hash_files: Vec::new(),
+            ..build_cache()
+        };
+        let (Commands::Pull(mut args), matches) = parse(&["pull", "build"]) else {
+            unreachable!();
+        };
+        configure_pull(&mut args, "build", &cache, &matches).unwrap();
+        assert_eq!(args.prefix, "build-linux");
+        assert_eq!(args.files, strings(&["target"]));
+        assert_eq!(args.fixed_key.as_deref(), Some("nightly"));
+        assert_eq!(args.fallback.fallback_key, strings(&["nightly"]));
+        assert_eq!(args.restore_key, strings(&["build-"]));
+
+        let (Commands::Pull(mut args), matches) = parse(&[
+            "pull",
+            "build",
+            "--tree-path",
+            "src",
+            "--restore-key",
+            "build-linux-",
+        ]) else {
+            unreachable!();
+        };
+        configure_pull(&mut args, "build", &cache, &matches).unwrap();
+        assert_eq!(args.tree_path, strings(&["src"]));
+        assert_eq!(args.fixed_key, None);
+        assert_eq!(args.restore_key, strings(&["build-linux-"]));
+        assert_eq!(args.fallback.fallback_key, strings(&["nightly"]));
+    }
+
+    #[test]
+    fn exec_arguments_override_the_configuration() {
+        let (Commands::Exec(mut args), matches) = parse(&[
+            "exec",
+            "build",
+            "--hash-files",
+            "Cargo.toml",
+            "--fallback-key",
+            "main",
+            "--",
+            "cargo",
+            "build",
+        ]) else {
+            unreachable!();
+        };
+        configure_exec(&mut args, "build", &build_cache(), &matches).unwrap();
+        assert_eq!(args.push.prefix, "build");
+        assert_eq!(args.push.files, strings(&["target"]));
+        assert_eq!(args.push.hash_files, strings(&["Cargo.toml"]));
+        assert_eq!(args.fallback.fallback_key, strings(&["main"]));
+        assert_eq!(args.restore_key, strings(&["build-"]));
+        assert_eq!(args.command, strings(&["cargo", "build"]));
+    }
+
+    #[test]
+    fn rejects_invalid_configured_values() {
+        let cache = CacheConfig {
+            compression: Some("lzma".to_string()),
+            ..build_cache()
+        };
+        let (Commands::Push(mut args), matches) = parse(&["push", "build"]) else {
+            unreachable!();
+        };
+        assert!(configure_push(&mut args, "build", &cache, &matches).is_err());
+    }
+
+    fn backend_args(location: &str, backend: Option<BackendKind>) -> BackendArgs {
+        BackendArgs {
+            location: location.to_string(),
+            backend,
+        }
+    }
+
+    #[test]
+    fn selects_the_backend_of_the_location() {
+        let folder = |path: &str| StorageLocation::Folder(PathBuf::from(path));
+        let cases = [
+            ("/tmp/caches", None, folder("/tmp/caches")),
+            ("caches", None, folder("caches")),
+            ("file:///tmp/caches", None, folder("/tmp/caches")),
+            (
+                "s3://bucket/team/caches",
+                None,
+                StorageLocation::S3 {
+                    bucket: "bucket",
+                    prefix: "team/caches",
+                },
+            ),
+            (
+                "s3://bucket",
+                None,
+                StorageLocation::S3 {
+                    bucket: "bucket",
+                    prefix: "",
+                },
+            ),
+            (
+                "http://cache:8080/caches",
+                None,
+                StorageLocation::Http("http://cache:8080/caches".to_string()),
+            ),
+            (
+                "https://cache/caches",
+                None,
+                StorageLocation::Http("https://cache/caches".to_string()),
+            ),
+            // --backend replaces the scheme
+            (
+                "bucket/caches",
+                Some(BackendKind::S3),
+                StorageLocation::S3 {
+                    bucket: "bucket",
+                    prefix: "caches",
+                },
+            ),
+            (
+                "cache:8080/caches",
+                Some(BackendKind::Http),
+                StorageLocation::Http("http://cache:8080/caches".to_string()),
+            ),
+            (
+                "https://cache/caches",
+                Some(BackendKind::Http),
+                StorageLocation::Http("https://cache/caches".to_string()),
+            ),
+            (
+                "nfs://server/caches",
+                Some(BackendKind::Folder),
+                folder("server/caches"),
+            ),
+        ];
+        for (location, backend, expected) in cases {
+            let args = backend_args(location, backend);
+            assert_eq!(storage_location(&args).unwrap(), expected, "{}", location);
+        }
+
+        let err = storage_location(&backend_args("gs://bucket/caches", None)).unwrap_err();
+        assert_eq!(err.to_string(), "Unsupported storage location scheme 'gs'");
+    }
+}
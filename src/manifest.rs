@@ -0,0 +1,163 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::time::SystemTime;
+
+use crate::exclude::Exclusions;
+
+/// Name of the manifest entry, always the first entry of the archive.
+/// It can't collide with the cached paths as they are stored under their hash.
+pub const MANIFEST_PATH: &str = "cache-thing-manifest.json";
+
+/// Description of the content of an archive
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    /// Version of cache-thing that created the archive
+    pub version: String,
+    pub host: Option<String>,
+    /// Commit checked out when the archive was created
+    pub commit: Option<String>,
+    pub created: SystemTime,
+    pub paths: Vec<PathEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PathEntry {
+    /// Path as given on the command line
+    pub path: String,
+    /// Name of the path in the archive
+    pub hash: String,
+    /// Number of files and directories
+    pub files: u64,
+    /// Total size of the regular files
+    pub size: u64,
+    /// Digest of the content, only recorded with `--skip-if-unchanged`
+    #[serde(default)]
+    pub digest: Option<String>,
+}
+
+impl Manifest {
+    pub fn new(commit: Option<String>) -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            host: hostname(),
+            commit,
+            created: SystemTime::now(),
+            paths: Vec::new(),
+        }
+    }
+
+    /// Add a path to the manifest, walking it to count its files.
+    pub fn add_path(
+        &mut self,
+        path: &str,
+        hash: String,
+        digest: Option<String>,
+        exclusions: &mut Exclusions,
+    ) -> io::Result<&PathEntry> {
+        let (files, size) = path_stats(Path::new(path), exclusions)?;
+        self.paths.push(PathEntry {
+            path: path.to_string(),
+            hash,
+            files,
+            size,
+            digest,
+        });
+        Ok(self.paths.last().unwrap())
+    }
+
+    pub fn find(&self, hash: &str) -> Option<&PathEntry> {
+        self.paths.iter().find(|entry| entry.hash == hash)
+    }
+
+    pub fn append_to<W: Write>(&self, archive: &mut tar::Builder<W>) -> io::Result<()> {
+        let data = serde_json::to_vec_pretty(self)?;
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(
+            self.created
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        );
+        archive.append_data(&mut header, MANIFEST_PATH, data.as_slice())
+    }
+
+    pub fn read_from(entry: impl Read) -> io::Result<Self> {
+        serde_json::from_reader(entry).map_err(io::Error::other)
+    }
+}
+
+/// Count the entries and the size of the regular files under `path`, following links like the archive builder.
+fn path_stats(path: &Path, exclusions: &mut Exclusions) -> io::Result<(u64, u64)> {
+    let metadata = std::fs::metadata(path)?;
+    if metadata.is_file() {
+        return Ok((1, metadata.len()));
+    }
+    if !metadata.is_dir() {
+        // Not archived
+        return Ok((0, 0));
+    }
+
+    let mut files = 1;
+    let mut size = 0;
+    for entry in std::fs::read_dir(path)? {
+        let entry_path = entry?.path();
+        if exclusions.is_excluded(&entry_path, entry_path.is_dir())? {
+            continue;
+        }
+        let (entry_files, entry_size) = path_stats(&entry_path, exclusions)?;
+        files += entry_files;
+        size += entry_size;
+    }
+    Ok((files, size))
+}
+
+/// Digest of the names and content of the files under `path`, following links like the archive builder.
+pub fn path_digest(path: &Path, exclusions: &mut Exclusions) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    hash_path(path, Path::new(""), &mut hasher, exclusions)?;
+    Ok(base16ct::lower::encode_string(&hasher.finalize()))
+}
+
+fn hash_path(
+    path: &Path,
+    relative: &Path,
+    hasher: &mut Sha256,
+    exclusions: &mut Exclusions,
+) -> io::Result<()> {
+    let metadata = std::fs::metadata(path)?;
+    hasher.update(relative.as_os_str().as_encoded_bytes());
+    hasher.update([0]);
+
+    if metadata.is_dir() {
+        hasher.update(b"d");
+        let mut names = std::fs::read_dir(path)?
+            .map(|entry| entry.map(|entry| entry.file_name()))
+            .collect::<io::Result<Vec<_>>>()?;
+        // The order of read_dir is not stable
+        names.sort();
+        for name in names {
+            let entry_path = path.join(&name);
+            if exclusions.is_excluded(&entry_path, entry_path.is_dir())? {
+                continue;
+            }
+            hash_path(&entry_path, &relative.join(&name), hasher, exclusions)?;
+        }
+    } else if metadata.is_file() {
+        hasher.update(b"f");
+        hasher.update(metadata.len().to_le_bytes());
+        io::copy(&mut std::fs::File::open(path)?, hasher)?;
+    }
+    Ok(())
+}
+
+fn hostname() -> Option<String> {
+    std::env::var("HOSTNAME")
+        .ok()
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+}
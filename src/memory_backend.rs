@@ -0,0 +1,99 @@
+use sha2::{Digest, Sha256};
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::rc::Rc;
+use std::time::SystemTime;
+
+use crate::storage_backend::{
+    CacheEntry, DIGEST_SUFFIX, QUARANTINE_PREFIX, StorageBackend, StorageWriter,
+};
+
+/// Backend keeping the caches in memory for the tests.
+/// Stores the digests as separate objects like the remote backends, the clones share the objects.
+#[derive(Default, Clone)]
+pub struct MemoryBackend {
+    pub objects: Rc<RefCell<HashMap<String, Vec<u8>>>>,
+    /// Number of `exists` calls
+    pub lookups: Rc<Cell<usize>>,
+}
+
+pub struct MemoryWriter<'s> {
+    backend: &'s MemoryBackend,
+    key: String,
+    data: Vec<u8>,
+}
+
+impl Write for MemoryWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.data.write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl StorageWriter for MemoryWriter<'_> {
+    fn finish(&mut self) -> io::Result<()> {
+        let digest = base16ct::lower::encode_string(&Sha256::digest(&self.data));
+        let mut objects = self.backend.objects.borrow_mut();
+        objects.insert(self.key.clone(), self.data.clone());
+        objects.insert(format!("{}{}", self.key, DIGEST_SUFFIX), digest.into());
+        Ok(())
+    }
+}
+
+impl StorageBackend for MemoryBackend {
+    type Error = io::Error;
+    fn writer<'s>(&'s self, key: &str) -> io::Result<impl StorageWriter + use<'s>> {
+        Ok(MemoryWriter {
+            backend: self,
+            key: key.to_string(),
+            data: Vec::new(),
+        })
+    }
+    fn reader<'s>(&'s self, key: &str) -> io::Result<impl io::Read + use<'s>> {
+        let data = self.objects.borrow().get(key).cloned();
+        data.map(io::Cursor::new)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))
+    }
+    fn exists(&self, key: &str) -> io::Result<bool> {
+        self.lookups.set(self.lookups.get() + 1);
+        Ok(self.objects.borrow().contains_key(key))
+    }
+    fn list(&self, prefix: &str) -> io::Result<Vec<CacheEntry>> {
+        Ok(self
+            .objects
+            .borrow()
+            .iter()
+            .filter(|(key, _)| {
+                key.starts_with(prefix)
+                    && !key.ends_with(DIGEST_SUFFIX)
+                    && !key.starts_with(QUARANTINE_PREFIX)
+            })
+            .map(|(key, data)| CacheEntry {
+                key: key.clone(),
+                size: data.len() as u64,
+                created: SystemTime::UNIX_EPOCH,
+                last_access: None,
+            })
+            .collect())
+    }
+    fn delete(&self, key: &str) -> io::Result<()> {
+        let mut objects = self.objects.borrow_mut();
+        objects.remove(key);
+        objects.remove(&format!("{}{}", key, DIGEST_SUFFIX));
+        Ok(())
+    }
+    fn digest(&self, key: &str) -> io::Result<Option<String>> {
+        let objects = self.objects.borrow();
+        let digest = objects.get(&format!("{}{}", key, DIGEST_SUFFIX));
+        Ok(digest.map(|digest| String::from_utf8_lossy(digest).into_owned()))
+    }
+    fn store_digest(&self, key: &str, digest: &str) -> io::Result<()> {
+        self.objects
+            .borrow_mut()
+            .insert(format!("{}{}", key, DIGEST_SUFFIX), digest.into());
+        Ok(())
+    }
+}
@@ -0,0 +1,151 @@
+use anyhow::Result;
+use filetime::FileTime;
+use log::{trace, warn};
+use std::fs::Metadata;
+use std::io::Write;
+use std::path::Path;
+use tar::{EntryType, Header, HeaderMode};
+
+use crate::exclude::Exclusions;
+
+/// Name of the PAX extended headers, like GNU tar
+const PAX_HEADER_NAME: &str = "././@PaxHeader";
+
+/// Append `path` to the archive under `name`, following symbolic links.
+///
+/// Directories are walked in name order so the same files give the same archive,
+/// the excluded files below `path` are left out.
+/// The headers record the permissions, the owner and the modification time,
+/// a PAX extended header adds the sub-second part of the modification time
+/// (tools like cargo compare them to the nanosecond) and the extended attributes if `xattrs` is set.
+pub fn append_path<W: Write>(
+    archive: &mut tar::Builder<W>,
+    name: &Path,
+    path: &Path,
+    xattrs: bool,
+    exclusions: &mut Exclusions,
+) -> Result<()> {
+    let metadata = std::fs::metadata(path)?;
+    if metadata.is_dir() {
+        trace!("Adding directory {:?} as {:?}", path, name);
+        append_entry(archive, name, path, &metadata, xattrs, std::io::empty())?;
+
+        let mut entries = std::fs::read_dir(path)?.collect::<Result<Vec<_>, _>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
+            let entry_path = entry.path();
+            if exclusions.is_excluded(&entry_path, entry_path.is_dir())? {
+                continue;
+            }
+            append_path(
+                archive,
+                &name.join(entry.file_name()),
+                &entry_path,
+                xattrs,
+                exclusions,
+            )?;
+        }
+    } else if metadata.is_file() {
+        trace!("Adding file {:?} as {:?}", path, name);
+        let file = std::fs::File::open(path)?;
+        append_entry(archive, name, path, &metadata, xattrs, file)?;
+    } else {
+        // Sockets, fifos and devices are not restored
+        warn!(
+            "Skipping {:?}, it is not a regular file or a directory",
+            path
+        );
+    }
+    Ok(())
+}
+
+fn append_entry<W: Write>(
+    archive: &mut tar::Builder<W>,
+    name: &Path,
+    path: &Path,
+    metadata: &Metadata,
+    xattrs: bool,
+    data: impl std::io::Read,
+) -> Result<()> {
+    let mut extensions = Vec::new();
+    let mtime = FileTime::from_last_modification_time(metadata);
+    if mtime.nanoseconds() != 0 && mtime.unix_seconds() >= 0 {
+        let value = format!("{}.{:09}", mtime.unix_seconds(), mtime.nanoseconds());
+        pax_record(&mut extensions, "mtime", value.as_bytes());
+    }
+    if xattrs {
+        append_xattrs(&mut extensions, path)?;
+    }
+    if !extensions.is_empty() {
+        let mut header = Header::new_ustar();
+        header.set_entry_type(EntryType::XHeader);
+        header.set_mode(0o644);
+        header.set_size(extensions.len() as u64);
+        archive.append_data(&mut header, PAX_HEADER_NAME, extensions.as_slice())?;
+    }
+
+    let mut header = Header::new_gnu();
+    header.set_metadata_in_mode(metadata, HeaderMode::Complete);
+    archive.append_data(&mut header, name, data)?;
+    Ok(())
+}
+
+/// Extended attributes in the format read by tar when unpacking
+#[cfg(unix)]
+fn append_xattrs(extensions: &mut Vec<u8>, path: &Path) -> Result<()> {
+    for attribute in xattr::list(path)? {
+        let Some(value) = xattr::get(path, &attribute)? else {
+            continue;
+        };
+        let key = format!("SCHILY.xattr.{}", attribute.to_string_lossy());
+        pax_record(extensions, &key, &value);
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn append_xattrs(_extensions: &mut Vec<u8>, path: &Path) -> Result<()> {
+    warn!(
+        "Extended attributes of {:?} are not archived on this platform",
+        path
+    );
+    Ok(())
+}
+
+/// `<length> <key>=<value>\n`, the length includes its own digits
+fn pax_record(extensions: &mut Vec<u8>, key: &str, value: &[u8]) {
+    let rest = key.len() + value.len() + 3;
+    let mut length = rest;
+    loop {
+        let total = rest + length.to_string().len();
+        if total == length {
+            break;
+        }
+        length = total;
+    }
+    extensions.extend_from_slice(format!("{} {}=", length, key).as_bytes());
+    extensions.extend_from_slice(value);
+    extensions.push(b'\n');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pax_record_length_includes_its_digits() {
+        let mut extensions = Vec::new();
+        pax_record(&mut extensions, "mtime", b"1714557600.123456789");
+        assert_eq!(extensions, b"30 mtime=1714557600.123456789\n");
+
+        // 9 bytes without the length, 10 with one digit, 11 with two
+        let mut extensions = Vec::new();
+        pax_record(&mut extensions, "k", b"abcde");
+        assert_eq!(extensions, b"11 k=abcde\n");
+
+        let mut extensions = Vec::new();
+        pax_record(&mut extensions, "key", &[b'v'; 92]);
+        assert_eq!(extensions.len(), 101);
+        assert!(extensions.starts_with(b"101 key=v"));
+    }
+}
@@ -0,0 +1,226 @@
+use anyhow::Result;
+use clap::Args;
+use log::{debug, info};
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+
+use crate::cache_key::{cache_name, list_caches};
+use crate::storage_backend::{CacheEntry, DynStorageBackend};
+
+/// Limits enforced when pruning the stored caches.
+/// The least recently used caches are evicted first.
+#[derive(Debug, Args)]
+pub struct PruneLimits {
+    /// Maximum total size of the caches, for example 20G or 500M
+    #[arg(long, env = "CACHE_THING_MAX_SIZE", value_parser = parse_size)]
+    pub max_size: Option<u64>,
+
+    /// Evict caches not used for this long, for example 7d or 12h
+    #[arg(long, env = "CACHE_THING_MAX_AGE", value_parser = humantime::parse_duration)]
+    pub max_age: Option<Duration>,
+
+    /// Maximum number of caches to keep for each cache name
+    #[arg(long, env = "CACHE_THING_MAX_ENTRIES")]
+    pub max_entries: Option<usize>,
+}
+
+impl PruneLimits {
+    pub fn is_set(&self) -> bool {
+        self.max_size.is_some() || self.max_age.is_some() || self.max_entries.is_some()
+    }
+}
+
+/// Evict the caches that exceed the limits, only the caches named `prefix` if it is given.
+/// Returns the evicted caches, nothing is deleted if `dry_run` is set.
+pub fn prune(
+    backend: &dyn DynStorageBackend,
+    prefix: Option<&str>,
+    limits: &PruneLimits,
+    dry_run: bool,
+) -> Result<Vec<CacheEntry>> {
+    let entries = list_caches(backend, prefix)?;
+
+    let mut evicted = Vec::new();
+    for (entry, reason) in select_evictions(entries, limits, SystemTime::now()) {
+        info!("Evicting cache {} ({})", entry.key, reason);
+        if !dry_run {
+            backend.delete(&entry.key)?;
+        }
+        evicted.push(entry);
+    }
+    Ok(evicted)
+}
+
+/// Caches to evict and why, the least recently used go first.
+/// The number of entries is limited per cache name, the size is the total of all the caches:
+/// once it is reached, every less recently used cache is evicted.
+fn select_evictions(
+    mut entries: Vec<CacheEntry>,
+    limits: &PruneLimits,
+    now: SystemTime,
+) -> Vec<(CacheEntry, &'static str)> {
+    // Most recently used first
+    entries.sort_by_key(|entry| std::cmp::Reverse(entry.last_used()));
+
+    let mut kept_size = 0;
+    let mut size_exceeded = false;
+    let mut kept_entries = HashMap::new();
+    let mut evicted = Vec::new();
+
+    for entry in entries {
+        let unused_for = now
+            .duration_since(entry.last_used())
+            .unwrap_or(Duration::ZERO);
+        // Keys without a generated part are counted on their own
+        let name = cache_name(&entry.key).unwrap_or(&entry.key).to_string();
+        let kept_entries = kept_entries.entry(name).or_insert(0);
+
+        let reason = if limits.max_age.is_some_and(|max_age| unused_for > max_age) {
+            Some("too old")
+        } else if limits
+            .max_entries
+            .is_some_and(|max_entries| *kept_entries >= max_entries)
+        {
+            Some("too many entries")
+        } else if limits
+            .max_size
+            .is_some_and(|max_size| size_exceeded || kept_size + entry.size > max_size)
+        {
+            size_exceeded = true;
+            Some("total size exceeded")
+        } else {
+            None
+        };
+
+        match reason {
+            Some(reason) => evicted.push((entry, reason)),
+            None => {
+                debug!("Keeping cache {}", entry.key);
+                kept_size += entry.size;
+                *kept_entries += 1;
+            }
+        }
+    }
+
+    evicted
+}
+
+/// Parse a size with an optional binary unit suffix (K, M, G, T).
+fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let digits_end = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(digits_end);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid size '{}'", value))?;
+
+    let multiplier: u64 = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1 << 10,
+        "M" | "MB" | "MIB" => 1 << 20,
+        "G" | "GB" | "GIB" => 1 << 30,
+        "T" | "TB" | "TIB" => 1 << 40,
+        _ => return Err(format!("unknown size unit '{}'", unit)),
+    };
+
+    number
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("size '{}' is too large", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: Duration = Duration::from_secs(60 * 60);
+
+    fn entry(name: &str, commit: char, size: u64, hours_ago: u64, now: SystemTime) -> CacheEntry {
+        CacheEntry {
+            key: format!("{}-{}", name, commit.to_string().repeat(40)),
+            size,
+            created: now - HOUR * hours_ago as u32,
+            last_access: None,
+        }
+    }
+
+    fn limits(
+        max_size: Option<u64>,
+        max_age: Option<Duration>,
+        max_entries: Option<usize>,
+    ) -> PruneLimits {
+        PruneLimits {
+            max_size,
+            max_age,
+            max_entries,
+        }
+    }
+
+    fn evicted_keys(evicted: &[(CacheEntry, &str)]) -> Vec<String> {
+        evicted.iter().map(|(entry, _)| entry.key.clone()).collect()
+    }
+
+    #[test]
+    fn evicts_older_entries_once_the_size_is_exceeded() {
+        let now = SystemTime::now();
+        let entries = vec![
+            entry("build", 'a', 10, 1, now),
+            entry("build", 'b', 100, 2, now),
+            entry("build", 'c', 10, 3, now),
+        ];
+        let evicted = select_evictions(entries, &limits(Some(50), None, None), now);
+        // The small oldest entry is not kept in place of the bigger recent one
+        assert_eq!(
+            evicted_keys(&evicted),
+            [
+                format!("build-{}", "b".repeat(40)),
+                format!("build-{}", "c".repeat(40))
+            ]
+        );
+        assert!(
+            evicted
+                .iter()
+                .all(|(_, reason)| *reason == "total size exceeded")
+        );
+    }
+
+    #[test]
+    fn limits_entries_per_cache_name() {
+        let now = SystemTime::now();
+        let entries = vec![
+            entry("build", 'a', 1, 1, now),
+            entry("build-linux", 'b', 1, 2, now),
+            entry("build", 'c', 1, 3, now),
+            entry("build-linux", 'd', 1, 4, now),
+            entry("build", 'e', 1, 5, now),
+        ];
+        let evicted = select_evictions(entries, &limits(None, None, Some(2)), now);
+        assert_eq!(
+            evicted_keys(&evicted),
+            [format!("build-{}", "e".repeat(40))]
+        );
+    }
+
+    #[test]
+    fn evicts_unused_entries() {
+        let now = SystemTime::now();
+        let mut recently_pulled = entry("build", 'b', 1, 48, now);
+        recently_pulled.last_access = Some(now - HOUR);
+        let entries = vec![entry("build", 'a', 1, 30, now), recently_pulled];
+        let evicted = select_evictions(entries, &limits(None, Some(HOUR * 24), None), now);
+        assert_eq!(
+            evicted_keys(&evicted),
+            [format!("build-{}", "a".repeat(40))]
+        );
+        assert_eq!(evicted[0].1, "too old");
+    }
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("20G"), Ok(20 << 30));
+        assert_eq!(parse_size("500 MiB"), Ok(500 << 20));
+        assert!(parse_size("12X").is_err());
+    }
+}
@@ -0,0 +1,74 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+/// Directory, relative to the git directory, where the state is stored
+const STATE_DIR: &str = "cache-thing";
+
+/// The cache last restored by `pull`, read by `push --skip-if-unchanged`.
+/// Stored in the git directory so it never ends up in a commit.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RestoreState {
+    pub key: String,
+    pub paths: Vec<RestoredPath>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RestoredPath {
+    /// Path as given on the command line
+    pub path: String,
+    /// Digest of the content when the cache was pushed
+    pub digest: String,
+}
+
+impl RestoreState {
+    pub fn load(prefix: &str, suffix: Option<&str>) -> Result<Option<Self>> {
+        let file = match std::fs::File::open(state_path(prefix, suffix)?) {
+            Ok(file) => file,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        Ok(Some(serde_json::from_reader(file)?))
+    }
+
+    pub fn save(&self, prefix: &str, suffix: Option<&str>) -> Result<()> {
+        let path = state_path(prefix, suffix)?;
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(path, serde_json::to_vec_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// Forget the restored cache, for example when the restored paths have no recorded digest
+    pub fn clear(prefix: &str, suffix: Option<&str>) -> Result<()> {
+        match std::fs::remove_file(state_path(prefix, suffix)?) {
+            Err(err) if err.kind() != ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Digest recorded for `path`
+    pub fn digest(&self, path: &str) -> Option<&str> {
+        self.paths
+            .iter()
+            .find(|restored| restored.path == path)
+            .map(|restored| restored.digest.as_str())
+    }
+}
+
+/// One state file per cache name and suffix, the name is hashed as it can contain any character
+fn state_path(prefix: &str, suffix: Option<&str>) -> Result<PathBuf> {
+    let repository = gix::discover(".")?;
+    let mut hasher = Sha256::new();
+    hasher.update(prefix);
+    hasher.update([0]);
+    hasher.update(suffix.unwrap_or_default());
+    let name = base16ct::lower::encode_string(&hasher.finalize());
+    Ok(repository
+        .git_dir()
+        .join(STATE_DIR)
+        .join(format!("restored-{}.json", name)))
+}
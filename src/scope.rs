@@ -0,0 +1,426 @@
+use anyhow::{Result, bail};
+use clap::Args;
+use gix::Repository;
+use log::{debug, trace};
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::ci::CiContext;
+use crate::storage_backend::{CacheEntry, DigestedReader, DynStorageBackend, StorageWriter};
+
+/// Key prefix of the scoped caches
+const SCOPES_PREFIX: &str = "scopes/";
+
+/// Isolation of the caches per branch and merge request, so an untrusted build can't plant
+/// a cache restored by a protected branch.
+///
+/// Caches are pushed in the scope of the build. Protected branches only read the scopes of
+/// protected branches, other branches and merge requests read their own scope then the protected ones.
+/// Tags and detached commits get a scope of their own.
+///
+/// The scopes are only enforced by cache-thing: a build controls its environment
+/// (`GITHUB_REF`, `--protected-branch`, ...) and can write to any scope with the credentials it has.
+/// Real isolation needs storage credentials restricted to the own scope (a bucket policy on the
+/// `scopes/<scope>/` prefix for example), with write access to the protected scopes only given to
+/// the builds of protected branches.
+#[derive(Debug, Clone, Args)]
+pub struct ScopeArgs {
+    /// Store and look for caches in the scope of the current branch or merge request
+    #[arg(long, env = "CACHE_THING_SCOPED")]
+    pub scoped: bool,
+
+    /// Branch whose caches can be restored from every scope.
+    /// Can be repeated or comma-separated
+    #[arg(
+        long,
+        env = "CACHE_THING_PROTECTED_BRANCHES",
+        value_delimiter = ',',
+        default_values_t = ["main".to_string(), "master".to_string()]
+    )]
+    pub protected_branch: Vec<String>,
+}
+
+#[derive(Debug)]
+pub struct Scopes {
+    /// Scope the caches are pushed to
+    pub own: String,
+    /// Scopes the caches are restored from, in order
+    pub readable: Vec<String>,
+}
+
+impl ScopeArgs {
+    /// Scopes of the current build, `None` if scoping is disabled
+    pub fn scopes(&self, repository: &Repository, ci: &CiContext) -> Result<Option<Scopes>> {
+        if !self.scoped {
+            return Ok(None);
+        }
+        let checkout = Checkout {
+            branch: repository
+                .head_name()?
+                .map(|name| name.shorten().to_string()),
+            commit: repository.head_id()?.to_string(),
+        };
+        Ok(Some(self.scopes_of(ci, &checkout)))
+    }
+
+    fn scopes_of(&self, ci: &CiContext, checkout: &Checkout) -> Scopes {
+        let protected_scope = |branch: &str| format!("branch/{}", branch);
+
+        let own = if ci.merge_request {
+            // The source branch is chosen by the author of the merge request, it can be named like a protected one
+            match &ci.merge_request_id {
+                Some(id) => format!("merge-request/{}", id),
+                // Shared by nothing else, merge requests without id must not see each other's caches
+                None => format!("merge-request/commit/{}", checkout.commit),
+            }
+        } else if let Some(branch) = &ci.branch {
+            protected_scope(branch)
+        } else if let Some(tag) = &ci.tag {
+            format!("tag/{}", tag)
+        } else {
+            match &checkout.branch {
+                Some(branch) => protected_scope(branch),
+                None => format!("commit/{}", checkout.commit),
+            }
+        };
+
+        let mut readable = vec![own.clone()];
+        let is_protected = self
+            .protected_branch
+            .iter()
+            .any(|branch| protected_scope(branch) == own);
+        if !is_protected {
+            // The branch the merge request will be merged into is the closest
+            if let Some(target_branch) = &ci.target_branch
+                && self.protected_branch.contains(target_branch)
+            {
+                readable.push(protected_scope(target_branch));
+            }
+        }
+        for branch in &self.protected_branch {
+            let scope = protected_scope(branch);
+            if !readable.contains(&scope) {
+                readable.push(scope);
+            }
+        }
+
+        debug!(
+            "Caches are in scope {}, readable scopes {:?}",
+            own, readable
+        );
+        Scopes { own, readable }
+    }
+}
+
+/// What is checked out, for the builds the CI doesn't describe
+struct Checkout {
+    /// `None` if HEAD is detached
+    branch: Option<String>,
+    commit: String,
+}
+
+/// Backend restricted to the scopes of the build.
+/// Keys are given without scope, writes go to the own scope and reads look in the readable scopes in order.
+pub struct ScopedBackend {
+    inner: Box<dyn DynStorageBackend>,
+    scopes: Scopes,
+    /// Scope each key was found in, to avoid looking it up again
+    found_in: RefCell<HashMap<String, String>>,
+}
+
+impl ScopedBackend {
+    pub fn new(inner: Box<dyn DynStorageBackend>, scopes: Scopes) -> Self {
+        Self {
+            inner,
+            scopes,
+            found_in: RefCell::new(HashMap::new()),
+        }
+    }
+
+    fn scoped_key(scope: &str, key: &str) -> String {
+        format!("{}{}/{}", SCOPES_PREFIX, scope, key)
+    }
+
+    /// Key of the first readable scope containing `key`
+    fn resolve(&self, key: &str) -> Result<Option<String>> {
+        if let Some(scope) = self.found_in.borrow().get(key) {
+            return Ok(Some(Self::scoped_key(scope, key)));
+        }
+        for scope in &self.scopes.readable {
+            let scoped_key = Self::scoped_key(scope, key);
+            if self.inner.exists(&scoped_key)? {
+                self.remember(key, scope);
+                return Ok(Some(scoped_key));
+            }
+        }
+        Ok(None)
+    }
+
+    fn resolve_existing(&self, key: &str) -> Result<String> {
+        match self.resolve(key)? {
+            Some(scoped_key) => Ok(scoped_key),
+            None => bail!("No cache found with key {} in the readable scopes", key),
+        }
+    }
+
+    fn remember(&self, key: &str, scope: &str) {
+        trace!("Cache {} found in scope {}", key, scope);
+        self.found_in
+            .borrow_mut()
+            .insert(key.to_string(), scope.to_string());
+    }
+}
+
+impl DynStorageBackend for ScopedBackend {
+    fn writer<'a>(&'a self, key: &str) -> Result<Box<dyn StorageWriter + 'a>> {
+        self.inner.writer(&Self::scoped_key(&self.scopes.own, key))
+    }
+    fn reader<'a>(&'a self, key: &str) -> Result<Box<dyn std::io::Read + 'a>> {
+        self.inner.reader(&self.resolve_existing(key)?)
+    }
+    fn untracked_reader<'a>(&'a self, key: &str) -> Result<Box<dyn std::io::Read + 'a>> {
+        self.inner.untracked_reader(&self.resolve_existing(key)?)
+    }
+    fn reader_with_digest<'a>(
+        &'a self,
+        key: &str,
+        record_access: bool,
+    ) -> Result<DigestedReader<'a>> {
+        self.inner
+            .reader_with_digest(&self.resolve_existing(key)?, record_access)
+    }
+    fn exists(&self, key: &str) -> Result<bool> {
+        Ok(self.resolve(key)?.is_some())
+    }
+    fn find_first(&self, keys: &[String]) -> Result<Option<usize>> {
+        // Every key in every scope, a key in any scope is closer than the next key
+        let readable = &self.scopes.readable;
+        let scoped_keys = keys
+            .iter()
+            .flat_map(|key| readable.iter().map(|scope| Self::scoped_key(scope, key)))
+            .collect::<Vec<_>>();
+        let Some(index) = self.inner.find_first(&scoped_keys)? else {
+            return Ok(None);
+        };
+        let key_index = index / readable.len();
+        self.remember(&keys[key_index], &readable[index % readable.len()]);
+        Ok(Some(key_index))
+    }
+    fn list(&self, prefix: &str) -> Result<Vec<CacheEntry>> {
+        let mut entries: Vec<CacheEntry> = Vec::new();
+        for scope in &self.scopes.readable {
+            let scope_prefix = Self::scoped_key(scope, "");
+            for mut entry in self.inner.list(&Self::scoped_key(scope, prefix))? {
+                entry.key = entry.key.split_off(scope_prefix.len());
+                if entries.iter().any(|existing| existing.key == entry.key) {
+                    continue;
+                }
+                self.remember(&entry.key, scope);
+                entries.push(entry);
+            }
+        }
+        Ok(entries)
+    }
+    fn delete(&self, key: &str) -> Result<()> {
+        // Only the caches of the own scope can be removed
+        self.inner.delete(&Self::scoped_key(&self.scopes.own, key))
+    }
+    fn digest(&self, key: &str) -> Result<Option<String>> {
+        let scoped_key = self.resolve_existing(key)?;
+        self.inner.digest(&scoped_key)
+    }
+    fn quarantine(&self, key: &str) -> Result<()> {
+        self.inner
+            .quarantine(&Self::scoped_key(&self.scopes.own, key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_backend::MemoryBackend;
+    use crate::storage_backend::StorageBackend;
+    use std::io::{Read, Write};
+
+    const COMMIT: &str = "87290fc479c6fca21d6f369635ed8ffea5438a30";
+
+    fn scope_args() -> ScopeArgs {
+        ScopeArgs {
+            scoped: true,
+            protected_branch: vec!["main".to_string(), "release".to_string()],
+        }
+    }
+
+    fn scopes(ci: CiContext, branch: Option<&str>) -> (String, Vec<String>) {
+        let checkout = Checkout {
+            branch: branch.map(str::to_string),
+            commit: COMMIT.to_string(),
+        };
+        let scopes = scope_args().scopes_of(&ci, &checkout);
+        (scopes.own, scopes.readable)
+    }
+
+    fn some(value: &str) -> Option<String> {
+        Some(value.to_string())
+    }
+
+    #[test]
+    fn finds_the_scopes_of_the_build() {
+        let merge_request = CiContext {
+            merge_request: true,
+            merge_request_id: some("42"),
+            target_branch: some("release"),
+            ..CiContext::default()
+        };
+        assert_eq!(
+            scopes(merge_request, Some("main")),
+            (
+                "merge-request/42".to_string(),
+                vec![
+                    "merge-request/42".to_string(),
+                    "branch/release".to_string(),
+                    "branch/main".to_string(),
+                ]
+            )
+        );
+
+        // Never shared with another merge request
+        let without_id = CiContext {
+            merge_request: true,
+            target_branch: some("main"),
+            ..CiContext::default()
+        };
+        let own = format!("merge-request/commit/{}", COMMIT);
+        assert_eq!(
+            scopes(without_id, None),
+            (
+                own.clone(),
+                vec![own, "branch/main".to_string(), "branch/release".to_string()]
+            )
+        );
+
+        let protected = CiContext {
+            branch: some("release"),
+            ..CiContext::default()
+        };
+        assert_eq!(
+            scopes(protected, None),
+            (
+                "branch/release".to_string(),
+                vec!["branch/release".to_string(), "branch/main".to_string()]
+            )
+        );
+
+        let feature = CiContext {
+            branch: some("feature"),
+            ..CiContext::default()
+        };
+        assert_eq!(
+            scopes(feature, Some("main")),
+            (
+                "branch/feature".to_string(),
+                vec![
+                    "branch/feature".to_string(),
+                    "branch/main".to_string(),
+                    "branch/release".to_string(),
+                ]
+            )
+        );
+
+        let tag = CiContext {
+            tag: some("v1.0"),
+            ..CiContext::default()
+        };
+        assert_eq!(
+            scopes(tag, None),
+            (
+                "tag/v1.0".to_string(),
+                vec![
+                    "tag/v1.0".to_string(),
+                    "branch/main".to_string(),
+                    "branch/release".to_string(),
+                ]
+            )
+        );
+
+        // Without CI, the checked out branch or commit
+        assert_eq!(scopes(CiContext::default(), Some("main")).0, "branch/main");
+        let own = format!("commit/{}", COMMIT);
+        assert_eq!(
+            scopes(CiContext::default(), None),
+            (
+                own.clone(),
+                vec![own, "branch/main".to_string(), "branch/release".to_string()]
+            )
+        );
+    }
+
+    fn store(backend: &MemoryBackend, key: &str, data: &[u8]) {
+        let mut writer = StorageBackend::writer(backend, key).unwrap();
+        writer.write_all(data).unwrap();
+        writer.finish().unwrap();
+    }
+
+    fn scoped(backend: &MemoryBackend) -> ScopedBackend {
+        let scopes = Scopes {
+            own: "branch/feature".to_string(),
+            readable: vec![
+                "branch/feature".to_string(),
+                "branch/main".to_string(),
+                "branch/release".to_string(),
+            ],
+        };
+        ScopedBackend::new(Box::new(backend.clone()), scopes)
+    }
+
+    #[test]
+    fn finds_keys_in_the_readable_scopes() {
+        let backend = MemoryBackend::default();
+        store(&backend, "scopes/branch/release/build-a", b"release a");
+        store(&backend, "scopes/branch/main/build-b", b"main b");
+        store(&backend, "scopes/branch/feature/build-b", b"feature b");
+        store(&backend, "scopes/merge-request/1/build-a", b"planted");
+        let scoped = scoped(&backend);
+        let keys = ["build-c", "build-a", "build-b"].map(str::to_string);
+
+        // A key in any scope is closer than the next key
+        assert_eq!(scoped.find_first(&keys).unwrap(), Some(1));
+        assert_eq!(scoped.find_first(&keys[2..]).unwrap(), Some(0));
+        assert_eq!(scoped.find_first(&keys[..1]).unwrap(), None);
+
+        // The scopes found are remembered, reading doesn't look the keys up again
+        let lookups = backend.lookups.get();
+        let mut data = String::new();
+        scoped
+            .reader("build-a")
+            .unwrap()
+            .read_to_string(&mut data)
+            .unwrap();
+        assert_eq!(data, "release a");
+        data.clear();
+        scoped
+            .reader("build-b")
+            .unwrap()
+            .read_to_string(&mut data)
+            .unwrap();
+        assert_eq!(data, "feature b");
+        assert_eq!(backend.lookups.get(), lookups);
+    }
+
+    #[test]
+    fn writes_and_deletes_in_the_own_scope() {
+        let backend = MemoryBackend::default();
+        store(&backend, "scopes/branch/main/build-a", b"main a");
+        let scoped = scoped(&backend);
+
+        let mut writer = scoped.writer("build-a").unwrap();
+        writer.write_all(b"feature a").unwrap();
+        writer.finish().unwrap();
+        drop(writer);
+        assert!(StorageBackend::exists(&backend, "scopes/branch/feature/build-a").unwrap());
+
+        scoped.delete("build-a").unwrap();
+        assert!(!StorageBackend::exists(&backend, "scopes/branch/feature/build-a").unwrap());
+        assert!(StorageBackend::exists(&backend, "scopes/branch/main/build-a").unwrap());
+    }
+}
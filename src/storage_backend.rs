@@ -1,6 +1,232 @@
+use std::time::SystemTime;
+
+/// Suffix of the objects storing the digest of a cache, for backends that can't store metadata alongside
+pub const DIGEST_SUFFIX: &str = ".sha256";
+
+/// Key prefix under which corrupt caches are moved by the default `quarantine` implementation
+pub const QUARANTINE_PREFIX: &str = "quarantine/";
+
+/// Reader of a cache and the digest recorded for the data it reads
+pub type DigestedReader<'a> = (Box<dyn std::io::Read + 'a>, Option<String>);
+
 pub trait StorageBackend {
-    type Error: std::error::Error + Send + Sync;
-    fn writer(&self, key: &str) -> Result<impl std::io::Write, Self::Error>;
-    fn reader(&self, key: &str) -> Result<impl std::io::Read, Self::Error>;
+    type Error: std::error::Error + Send + Sync + 'static + From<std::io::Error>;
+    /// The writer can borrow the backend but not the key
+    fn writer<'s>(&'s self, key: &str) -> Result<impl StorageWriter + use<'s, Self>, Self::Error>;
+    fn reader<'s>(&'s self, key: &str) -> Result<impl std::io::Read + use<'s, Self>, Self::Error>;
+    /// Read a cache without recording an access, checking a cache is not using it.
+    /// Backends that track the last access must override it.
+    fn untracked_reader<'s>(
+        &'s self,
+        key: &str,
+    ) -> Result<impl std::io::Read + use<'s, Self>, Self::Error> {
+        self.reader(key)
+    }
+    /// Reader of a cache and the digest recorded for the data it reads, `None` for caches stored by older versions.
+    /// The access is recorded if `record_access` is set, like `reader` does.
+    /// By default they are looked up one after the other, backends must override it if a push
+    /// replacing the cache in between could pair the old data with the new digest.
+    fn reader_with_digest<'s>(
+        &'s self,
+        key: &str,
+        record_access: bool,
+    ) -> Result<DigestedReader<'s>, Self::Error> {
+        let reader: Box<dyn std::io::Read + 's> = if record_access {
+            Box::new(self.reader(key)?)
+        } else {
+            Box::new(self.untracked_reader(key)?)
+        };
+        Ok((reader, self.digest(key)?))
+    }
     fn exists(&self, key: &str) -> Result<bool, Self::Error>;
+    /// Index of the first of `keys` that exists.
+    /// Backends where a lookup is slow should check the keys in a single pass or concurrently.
+    fn find_first(&self, keys: &[String]) -> Result<Option<usize>, Self::Error> {
+        for (index, key) in keys.iter().enumerate() {
+            if self.exists(key)? {
+                return Ok(Some(index));
+            }
+        }
+        Ok(None)
+    }
+    /// List the stored caches whose key starts with `prefix`
+    fn list(&self, prefix: &str) -> Result<Vec<CacheEntry>, Self::Error>;
+    fn delete(&self, key: &str) -> Result<(), Self::Error>;
+    /// Hex-encoded SHA-256 of the stored archive, recorded by the writer.
+    /// Caches stored by older versions have no digest.
+    fn digest(&self, key: &str) -> Result<Option<String>, Self::Error>;
+    /// Replace the digest recorded for a stored cache, used by the default `quarantine`
+    fn store_digest(&self, key: &str, _digest: &str) -> Result<(), Self::Error> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            format!("cannot store the digest of cache {}", key),
+        )
+        .into())
+    }
+
+    /// Move a cache out of the way so it is never restored, keeping it for inspection.
+    /// The copy keeps the original digest, a digest of the corrupt data would make it look valid.
+    fn quarantine(&self, key: &str) -> Result<(), Self::Error> {
+        let digest = self.digest(key)?;
+        let mut reader = self.untracked_reader(key)?;
+        let quarantine_key = format!("{}{}", QUARANTINE_PREFIX, key);
+        let mut writer = self.writer(&quarantine_key)?;
+        std::io::copy(&mut reader, &mut writer)?;
+        writer.finish()?;
+        drop(writer);
+        drop(reader);
+        if let Some(digest) = digest {
+            self.store_digest(&quarantine_key, &digest)?;
+        }
+        self.delete(key)
+    }
+}
+
+/// Number of lookups running at the same time in [`find_first_concurrent`]
+const CONCURRENT_LOOKUPS: usize = 8;
+
+/// `find_first` for remote backends, checks batches of keys concurrently.
+/// The first existing key in the order of `keys` is returned, not the first to answer.
+pub fn find_first_concurrent<E: Send>(
+    keys: &[String],
+    exists: impl Fn(&str) -> Result<bool, E> + Sync,
+) -> Result<Option<usize>, E> {
+    let exists = &exists;
+    for (batch_index, batch) in keys.chunks(CONCURRENT_LOOKUPS).enumerate() {
+        let results = std::thread::scope(|scope| {
+            let lookups = batch
+                .iter()
+                .map(|key| scope.spawn(move || exists(key)))
+                .collect::<Vec<_>>();
+            lookups
+                .into_iter()
+                .map(|lookup| lookup.join().expect("lookup thread panicked"))
+                .collect::<Vec<_>>()
+        });
+        for (index, result) in results.into_iter().enumerate() {
+            if result? {
+                return Ok(Some(batch_index * CONCURRENT_LOOKUPS + index));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Information about a stored cache
+#[derive(Debug, Clone)]
+pub struct CacheEntry {
+    pub key: String,
+    /// Size of the stored archive in bytes
+    pub size: u64,
+    pub created: SystemTime,
+    /// Not all backends can tell when a cache was last read
+    pub last_access: Option<SystemTime>,
+}
+
+impl CacheEntry {
+    /// Last time the cache was read or written
+    pub fn last_used(&self) -> SystemTime {
+        self.last_access.unwrap_or(self.created)
+    }
+}
+
+/// Writer returned by a storage backend.
+/// The data is only guaranteed to be stored under the key once `finish` returned successfully.
+pub trait StorageWriter: std::io::Write {
+    fn finish(&mut self) -> std::io::Result<()>;
+}
+
+/// Object-safe version of [`StorageBackend`], used to select the backend at runtime.
+/// It is implemented for every [`StorageBackend`].
+pub trait DynStorageBackend {
+    fn writer<'a>(&'a self, key: &str) -> anyhow::Result<Box<dyn StorageWriter + 'a>>;
+    fn reader<'a>(&'a self, key: &str) -> anyhow::Result<Box<dyn std::io::Read + 'a>>;
+    fn untracked_reader<'a>(&'a self, key: &str) -> anyhow::Result<Box<dyn std::io::Read + 'a>>;
+    fn reader_with_digest<'a>(
+        &'a self,
+        key: &str,
+        record_access: bool,
+    ) -> anyhow::Result<DigestedReader<'a>>;
+    fn exists(&self, key: &str) -> anyhow::Result<bool>;
+    fn find_first(&self, keys: &[String]) -> anyhow::Result<Option<usize>>;
+    fn list(&self, prefix: &str) -> anyhow::Result<Vec<CacheEntry>>;
+    fn delete(&self, key: &str) -> anyhow::Result<()>;
+    fn digest(&self, key: &str) -> anyhow::Result<Option<String>>;
+    fn quarantine(&self, key: &str) -> anyhow::Result<()>;
+}
+
+impl<T: StorageBackend> DynStorageBackend for T {
+    fn writer<'a>(&'a self, key: &str) -> anyhow::Result<Box<dyn StorageWriter + 'a>> {
+        Ok(Box::new(StorageBackend::writer(self, key)?))
+    }
+    fn reader<'a>(&'a self, key: &str) -> anyhow::Result<Box<dyn std::io::Read + 'a>> {
+        Ok(Box::new(StorageBackend::reader(self, key)?))
+    }
+    fn untracked_reader<'a>(&'a self, key: &str) -> anyhow::Result<Box<dyn std::io::Read + 'a>> {
+        Ok(Box::new(StorageBackend::untracked_reader(self, key)?))
+    }
+    fn reader_with_digest<'a>(
+        &'a self,
+        key: &str,
+        record_access: bool,
+    ) -> anyhow::Result<DigestedReader<'a>> {
+        Ok(StorageBackend::reader_with_digest(
+            self,
+            key,
+            record_access,
+        )?)
+    }
+    fn exists(&self, key: &str) -> anyhow::Result<bool> {
+        Ok(StorageBackend::exists(self, key)?)
+    }
+    fn find_first(&self, keys: &[String]) -> anyhow::Result<Option<usize>> {
+        Ok(StorageBackend::find_first(self, keys)?)
+    }
+    fn list(&self, prefix: &str) -> anyhow::Result<Vec<CacheEntry>> {
+        Ok(StorageBackend::list(self, prefix)?)
+    }
+    fn delete(&self, key: &str) -> anyhow::Result<()> {
+        Ok(StorageBackend::delete(self, key)?)
+    }
+    fn digest(&self, key: &str) -> anyhow::Result<Option<String>> {
+        Ok(StorageBackend::digest(self, key)?)
+    }
+    fn quarantine(&self, key: &str) -> anyhow::Result<()> {
+        Ok(StorageBackend::quarantine(self, key)?)
+    }
+}
+
+impl<W: StorageWriter + ?Sized> StorageWriter for Box<W> {
+    fn finish(&mut self) -> std::io::Result<()> {
+        (**self).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_backend::MemoryBackend;
+    use std::io::Write;
+
+    #[test]
+    fn quarantine_keeps_the_original_digest() {
+        let backend = MemoryBackend::default();
+        let mut writer = StorageBackend::writer(&backend, "build-abc").unwrap();
+        writer.write_all(b"cache data").unwrap();
+        writer.finish().unwrap();
+        drop(writer);
+        let original = StorageBackend::digest(&backend, "build-abc").unwrap();
+        backend.objects.borrow_mut().get_mut("build-abc").unwrap()[1] ^= 0x01;
+
+        StorageBackend::quarantine(&backend, "build-abc").unwrap();
+
+        let quarantine_key = format!("{}build-abc", QUARANTINE_PREFIX);
+        assert!(!StorageBackend::exists(&backend, "build-abc").unwrap());
+        assert!(StorageBackend::list(&backend, "").unwrap().is_empty());
+        assert!(StorageBackend::exists(&backend, &quarantine_key).unwrap());
+        assert_eq!(
+            StorageBackend::digest(&backend, &quarantine_key).unwrap(),
+            original
+        );
+    }
 }
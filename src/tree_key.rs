@@ -0,0 +1,56 @@
+use anyhow::Result;
+use gix::{ObjectId, Repository};
+use log::trace;
+use sha2::{Digest, Sha256};
+
+/// Name of the cache key part replacing the commit when the key is derived from trees
+pub const KEY_PART_PREFIX: &str = "tree-";
+
+/// Key part identifying the content of `paths` at `commit`.
+///
+/// With a single path it is the OID of its tree (or blob), with several paths a digest of their OIDs.
+/// Paths are relative to the root of the repository.
+/// Returns `None` if none of the paths exist at this commit.
+pub fn tree_key(
+    repository: &Repository,
+    commit: ObjectId,
+    paths: &[String],
+) -> Result<Option<String>> {
+    let tree = repository.find_commit(commit)?.tree()?;
+
+    let mut ids = Vec::new();
+    for path in paths {
+        let path = path.trim_matches('/');
+        let id = if path.is_empty() || path == "." {
+            Some(tree.id)
+        } else {
+            tree.lookup_entry_by_path(path)?
+                .map(|entry| entry.object_id())
+        };
+        trace!("Path {} is {:?} at commit {}", path, id, commit);
+        ids.push((path, id));
+    }
+
+    if ids.iter().all(|(_, id)| id.is_none()) {
+        return Ok(None);
+    }
+    if let [(_, Some(id))] = ids.as_slice() {
+        return Ok(Some(format!("{}{}", KEY_PART_PREFIX, id)));
+    }
+
+    let mut hasher = Sha256::new();
+    for (path, id) in ids {
+        hasher.update(path);
+        hasher.update([0]);
+        match id {
+            Some(id) => hasher.update(id.as_bytes()),
+            None => hasher.update(b"missing"),
+        }
+        hasher.update([0]);
+    }
+    Ok(Some(format!(
+        "{}{}",
+        KEY_PART_PREFIX,
+        base16ct::lower::encode_string(&hasher.finalize())
+    )))
+}
@@ -0,0 +1,744 @@
+use anyhow::{Result, bail};
+use clap::Args;
+use filetime::FileTime;
+use log::{debug, trace};
+use std::collections::VecDeque;
+use std::ffi::{OsStr, OsString};
+use std::io::{ErrorKind, Read};
+use std::path::{Component, Path, PathBuf};
+use tar::EntryType;
+
+/// Metadata given to the restored files
+#[derive(Debug, Clone, Args)]
+pub struct RestoreArgs {
+    /// Give the restored files the time of the extraction instead of their archived modification time
+    #[arg(long, conflicts_with = "touch_restored")]
+    pub no_preserve_mtime: bool,
+
+    /// Give the restored files the commit time of HEAD, so they are not older than the checked out sources.
+    /// Sources checked out later than the commit can still be newer, see --restored-mtime
+    #[arg(long, conflicts_with = "restored_mtime")]
+    pub touch_restored: bool,
+
+    /// Give the restored files this modification time, in seconds since the epoch or RFC 3339
+    /// (`2024-05-01T10:00:00Z`), for example the time of the checkout
+    #[arg(long, value_parser = parse_timestamp, conflicts_with = "no_preserve_mtime")]
+    pub restored_mtime: Option<FileTime>,
+
+    /// Apply the umask to the archived permissions and drop the setuid, setgid and sticky bits
+    #[arg(long)]
+    pub no_preserve_permissions: bool,
+
+    /// Give the restored files their archived owner and group, usually requires root
+    #[arg(long)]
+    pub preserve_ownership: bool,
+}
+
+/// How the modification time of the restored files is set
+enum Mtime {
+    Archived,
+    Extraction,
+    Fixed(FileTime),
+}
+
+/// Extracts the entries of a cache archive
+pub struct Unpacker {
+    mtime: Mtime,
+    permissions: bool,
+    ownership: bool,
+    xattrs: bool,
+    /// Modification times of the directories, set once their content is extracted
+    directories: Vec<(PathBuf, FileTime)>,
+    /// Extracted symbolic links, as the root and the path below it, resolved once they all exist
+    symlinks: Vec<(PathBuf, PathBuf)>,
+}
+
+/// Most symbolic links followed resolving a link, like the `ELOOP` limit of Linux
+const MAX_SYMLINK_HOPS: usize = 40;
+
+impl Unpacker {
+    pub fn new(args: &RestoreArgs, xattrs: bool) -> Result<Self> {
+        let mtime = if let Some(mtime) = args.restored_mtime {
+            Mtime::Fixed(mtime)
+        } else if args.touch_restored {
+            let repository = gix::discover(".")?;
+            let time = repository.head_commit()?.time()?;
+            let commit_time = FileTime::from_unix_time(time.seconds, 0);
+            debug!("Restored files get the commit time of HEAD {}", commit_time);
+            Mtime::Fixed(commit_time)
+        } else if args.no_preserve_mtime {
+            Mtime::Extraction
+        } else {
+            Mtime::Archived
+        };
+        Ok(Self {
+            mtime,
+            permissions: !args.no_preserve_permissions,
+            ownership: args.preserve_ownership,
+            xattrs,
+            directories: Vec::new(),
+            symlinks: Vec::new(),
+        })
+    }
+
+    /// Apply the options to the archive before reading its entries
+    pub fn configure<R: Read>(&self, archive: &mut tar::Archive<R>) {
+        // Set by the unpacker with the sub-second part
+        archive.set_preserve_mtime(false);
+        archive.set_preserve_permissions(self.permissions);
+        if !self.permissions {
+            archive.set_mask(umask());
+        }
+        archive.set_preserve_ownerships(self.ownership);
+        archive.set_unpack_xattrs(self.xattrs);
+    }
+
+    /// Extract an archive entry below `root`, the path the cache was restored to.
+    ///
+    /// Caches can be pushed by untrusted jobs (fork pull requests share the store),
+    /// the entry is refused if it would write outside of `root`: absolute paths or `..` components,
+    /// paths going through a symbolic link, symbolic links resolving outside of it
+    /// and hard links to other files than the ones of the cached path.
+    /// `hash` is the name of the cached path in the archive.
+    pub fn unpack_entry<R: Read>(
+        &mut self,
+        entry: &mut tar::Entry<R>,
+        root: &Path,
+        hash: &str,
+        path: &Path,
+    ) -> Result<()> {
+        let mtime = match self.mtime {
+            Mtime::Archived => Some(archived_mtime(entry)?),
+            Mtime::Extraction => None,
+            Mtime::Fixed(mtime) => Some(mtime),
+        };
+        let Some(output_path) = unpack_entry(entry, root, hash, path)? else {
+            return Ok(());
+        };
+        if entry.header().entry_type().is_symlink() {
+            let relative = output_path.strip_prefix(root)?.to_path_buf();
+            self.symlinks.push((root.to_path_buf(), relative));
+        }
+        let Some(mtime) = mtime else {
+            return Ok(());
+        };
+        if entry.header().entry_type().is_dir() {
+            // Extracting the content would change it
+            self.directories.push((output_path, mtime));
+        } else {
+            filetime::set_symlink_file_times(&output_path, mtime, mtime)?;
+        }
+        Ok(())
+    }
+
+    /// Check where the extracted symbolic links lead and set the modification time of the directories.
+    ///
+    /// Each link target was checked on its own, but a target can go through links extracted later:
+    /// with `l -> ..` a link `x -> l/..` leads to the parent of the root.
+    /// A link leading outside of the root is removed.
+    pub fn finish(self) -> Result<()> {
+        for (root, link) in &self.symlinks {
+            let is_symlink = std::fs::symlink_metadata(root.join(link))
+                .is_ok_and(|metadata| metadata.is_symlink());
+            if !is_symlink {
+                // Replaced by a later entry
+                continue;
+            }
+            if let Err(err) = check_resolved_link(root, link) {
+                std::fs::remove_file(root.join(link))?;
+                bail!("Removed symbolic link {}: {}", link.display(), err);
+            }
+        }
+        for (path, mtime) in self.directories.iter().rev() {
+            filetime::set_file_times(path, *mtime, *mtime).map_err(|err| {
+                anyhow::anyhow!("Could not set the modification time of {:?}: {}", path, err)
+            })?;
+        }
+        Ok(())
+    }
+}
+
+/// Modification time of the entry, to the nanosecond if the archive has it in a PAX header
+fn archived_mtime<R: Read>(entry: &mut tar::Entry<R>) -> Result<FileTime> {
+    if let Some(extensions) = entry.pax_extensions()? {
+        for extension in extensions {
+            let extension = extension?;
+            if extension.key() == Ok("mtime")
+                && let Some(mtime) = extension.value().ok().and_then(parse_pax_time)
+            {
+                return Ok(mtime);
+            }
+        }
+    }
+    Ok(FileTime::from_unix_time(entry.header().mtime()? as i64, 0))
+}
+
+/// `<seconds>[.<fraction>]`, times before 1970 are ignored
+fn parse_pax_time(value: &str) -> Option<FileTime> {
+    let (seconds, fraction) = value.split_once('.').unwrap_or((value, ""));
+    let seconds = seconds.parse::<u64>().ok()?;
+    if !fraction.bytes().all(|digit| digit.is_ascii_digit()) {
+        return None;
+    }
+    let nanoseconds = format!("{:0<9}", &fraction[..fraction.len().min(9)]);
+    Some(FileTime::from_unix_time(
+        seconds as i64,
+        nanoseconds.parse().ok()?,
+    ))
+}
+
+/// `--restored-mtime`, seconds since the epoch or an RFC 3339 time
+fn parse_timestamp(value: &str) -> Result<FileTime, String> {
+    if let Ok(seconds) = value.parse::<i64>() {
+        return Ok(FileTime::from_unix_time(seconds, 0));
+    }
+    let time = humantime::parse_rfc3339_weak(value).map_err(|err| err.to_string())?;
+    Ok(FileTime::from_system_time(time))
+}
+
+#[cfg(unix)]
+fn umask() -> u32 {
+    // The umask can only be read by setting it
+    unsafe {
+        let mask = libc::umask(0o022);
+        libc::umask(mask);
+        mask as u32
+    }
+}
+
+#[cfg(not(unix))]
+fn umask() -> u32 {
+    0o022
+}
+
+/// Extract the entry, returns the path it was extracted to unless it is a hard link
+fn unpack_entry<R: Read>(
+    entry: &mut tar::Entry<R>,
+    root: &Path,
+    hash: &str,
+    path: &Path,
+) -> Result<Option<PathBuf>> {
+    let mut components = path.components();
+    if components.next() != Some(Component::Normal(hash.as_ref())) {
+        bail!("path is not below the cached path");
+    }
+    let relative = relative_path(components)?;
+    let output_path = if relative.as_os_str().is_empty() {
+        root.to_path_buf()
+    } else {
+        root.join(&relative)
+    };
+    check_no_symlink(root, &relative)?;
+
+    match entry.header().entry_type() {
+        EntryType::Regular | EntryType::Continuous | EntryType::Directory => {}
+        EntryType::Symlink => {
+            let Some(target) = entry.link_name()? else {
+                bail!("symbolic link without target");
+            };
+            check_link_target(&relative, &target)?;
+        }
+        EntryType::Link => {
+            // tar would resolve the target relative to the working directory, not to the root
+            let Some(target) = entry.link_name()? else {
+                bail!("hard link without target");
+            };
+            let mut target_components = target.components();
+            if target_components.next() != Some(Component::Normal(hash.as_ref())) {
+                bail!(
+                    "hard link target {} is outside of the cached path",
+                    target.display()
+                );
+            }
+            let target_relative = relative_path(target_components)?;
+            check_no_symlink(root, &target_relative)?;
+            let target_path = root.join(target_relative);
+            // The link would be a copy of the symbolic link, its target resolved from another directory
+            if std::fs::symlink_metadata(&target_path).is_ok_and(|metadata| metadata.is_symlink()) {
+                bail!("hard link target {} is a symbolic link", target.display());
+            }
+            trace!("Linking {:?} to {:?}", output_path, target_path);
+            match std::fs::remove_file(&output_path) {
+                Err(err) if err.kind() != ErrorKind::NotFound => return Err(err.into()),
+                _ => {}
+            }
+            std::fs::hard_link(target_path, output_path)?;
+            return Ok(None);
+        }
+        entry_type => bail!("unsupported entry type {:?}", entry_type),
+    }
+
+    // tar would go through a symbolic link extracted earlier at this path:
+    // a directory entry changes the permissions of the link target, a file is written to it
+    if !relative.as_os_str().is_empty()
+        && std::fs::symlink_metadata(&output_path).is_ok_and(|metadata| metadata.is_symlink())
+    {
+        if entry.header().entry_type().is_dir() {
+            bail!("{} is a symbolic link", output_path.display());
+        }
+        std::fs::remove_file(&output_path)?;
+    }
+    entry.unpack(&output_path)?;
+    Ok(Some(output_path))
+}
+
+/// Path below the root, without `..` or absolute components that would leave it
+fn relative_path<'a>(components: impl Iterator<Item = Component<'a>>) -> Result<PathBuf> {
+    let mut path = PathBuf::new();
+    for component in components {
+        match component {
+            Component::Normal(name) => path.push(name),
+            Component::CurDir => {}
+            Component::ParentDir => bail!("path contains '..'"),
+            Component::RootDir | Component::Prefix(_) => bail!("path is absolute"),
+        }
+    }
+    Ok(path)
+}
+
+/// Check that the parents of `relative` below `root` are not symbolic links,
+/// an earlier entry could have created one pointing anywhere
+fn check_no_symlink(root: &Path, relative: &Path) -> Result<()> {
+    let mut path = root.to_path_buf();
+    let mut components = relative.components().peekable();
+    while let Some(component) = components.next() {
+        if components.peek().is_none() {
+            break;
+        }
+        path.push(component);
+        match std::fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.is_symlink() => {
+                bail!("{} is a symbolic link", path.display())
+            }
+            Ok(_) => {}
+            Err(err) if err.kind() == ErrorKind::NotFound => break,
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(())
+}
+
+/// Check that a symbolic link at `link`, relative to the root, points inside the root
+fn check_link_target(link: &Path, target: &Path) -> Result<()> {
+    if link.as_os_str().is_empty() {
+        bail!("the cached path itself is a symbolic link");
+    }
+
+    // Number of directories between the root and the current position
+    let mut depth = link.components().count() - 1;
+    for component in target.components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => {}
+            Component::ParentDir if depth > 0 => depth -= 1,
+            Component::ParentDir => bail!(
+                "symbolic link target {} is outside of the cached path",
+                target.display()
+            ),
+            Component::RootDir | Component::Prefix(_) => {
+                bail!("symbolic link target {} is absolute", target.display())
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Check that the symbolic link at `link`, relative to `root`, leads inside of it,
+/// following the links its target goes through
+fn check_resolved_link(root: &Path, link: &Path) -> Result<()> {
+    // Resolved directories below the root and the components left to resolve
+    let mut resolved: Vec<OsString> = link
+        .parent()
+        .map(|parent| parent.iter().map(OsStr::to_os_string).collect())
+        .unwrap_or_default();
+    let mut pending = VecDeque::new();
+    push_target(&mut pending, &std::fs::read_link(root.join(link))?)?;
+
+    let mut hops = 0;
+    while let Some(name) = pending.pop_front() {
+        let Some(name) = name else {
+            if resolved.pop().is_none() {
+                bail!("it leads outside of the cached path");
+            }
+            continue;
+        };
+        resolved.push(name);
+        let path = resolved
+            .iter()
+            .fold(root.to_path_buf(), |path, name| path.join(name));
+        match std::fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.is_symlink() => {
+                hops += 1;
+                if hops > MAX_SYMLINK_HOPS {
+                    bail!("too many levels of symbolic links");
+                }
+                resolved.pop();
+                push_target(&mut pending, &std::fs::read_link(&path)?)?;
+            }
+            Ok(_) => {}
+            // A missing path can't be gone through
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(())
+}
+
+/// Add the components of a link target in front of `pending`, `None` for `..`
+fn push_target(pending: &mut VecDeque<Option<OsString>>, target: &Path) -> Result<()> {
+    for component in target.components().rev() {
+        match component {
+            Component::Normal(name) => pending.push_front(Some(name.to_os_string())),
+            Component::CurDir => {}
+            Component::ParentDir => pending.push_front(None),
+            Component::RootDir | Component::Prefix(_) => {
+                bail!("symbolic link target {} is absolute", target.display())
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tar::Header;
+
+    const HASH: &str = "0123abcd";
+
+    /// Archive entry written with raw headers, `tar::Builder` refuses the malicious paths
+    enum Entry<'a> {
+        File(&'a str),
+        Dir(&'a str),
+        Symlink(&'a str, &'a str),
+        HardLink(&'a str, &'a str),
+    }
+
+    fn archive(entries: &[Entry]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for entry in entries {
+            let mut header = Header::new_gnu();
+            let (entry_type, path, target, data): (_, _, _, &[u8]) = match *entry {
+                Entry::File(path) => (EntryType::Regular, path, None, b"data"),
+                Entry::Dir(path) => (EntryType::Directory, path, None, b""),
+                Entry::Symlink(path, target) => (EntryType::Symlink, path, Some(target), b""),
+                Entry::HardLink(path, target) => (EntryType::Link, path, Some(target), b""),
+            };
+            header.set_entry_type(entry_type);
+            header.set_mode(if entry_type.is_dir() { 0o755 } else { 0o644 });
+            header.set_size(data.len() as u64);
+            let gnu = header.as_gnu_mut().unwrap();
+            gnu.name[..path.len()].copy_from_slice(path.as_bytes());
+            if let Some(target) = target {
+                gnu.linkname[..target.len()].copy_from_slice(target.as_bytes());
+            }
+            header.set_cksum();
+            builder.append(&header, data).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    fn restore_args(restored_mtime: Option<FileTime>) -> RestoreArgs {
+        RestoreArgs {
+            no_preserve_mtime: false,
+            touch_restored: false,
+            restored_mtime,
+            no_preserve_permissions: false,
+            preserve_ownership: false,
+        }
+    }
+
+    /// Extract the entries to `<dir>/cached` like a pull
+    fn extract(dir: &Path, entries: &[Entry]) -> Result<()> {
+        unpack_archive(&archive(entries), &dir.join("cached"), &restore_args(None))
+    }
+
+    fn unpack_archive(data: &[u8], root: &Path, args: &RestoreArgs) -> Result<()> {
+        let mut unpacker = Unpacker::new(args, false)?;
+        let mut archive = tar::Archive::new(data);
+        unpacker.configure(&mut archive);
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let path = entry.path()?.into_owned();
+            unpacker.unpack_entry(&mut entry, root, HASH, &path)?;
+        }
+        unpacker.finish()
+    }
+
+    /// Archive `<dir>/source` with a file whose modification time has nanoseconds
+    fn archive_with_mtime(dir: &Path, mtime: FileTime) -> Vec<u8> {
+        let source = dir.join("source");
+        std::fs::create_dir(&source).unwrap();
+        std::fs::write(source.join("file"), b"data").unwrap();
+        filetime::set_file_mtime(source.join("file"), mtime).unwrap();
+        filetime::set_file_mtime(&source, mtime).unwrap();
+
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut exclusions = crate::exclude::Exclusions::new(None, &[]).unwrap();
+        crate::pack::append_path(
+            &mut builder,
+            Path::new(HASH),
+            &source,
+            false,
+            &mut exclusions,
+        )
+        .unwrap();
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    fn parses_pax_times() {
+        assert_eq!(
+            parse_pax_time("1714557600.123456789"),
+            Some(FileTime::from_unix_time(1714557600, 123456789))
+        );
+        assert_eq!(
+            parse_pax_time("1714557600.5"),
+            Some(FileTime::from_unix_time(1714557600, 500000000))
+        );
+        // Digits past the nanosecond are truncated
+        assert_eq!(
+            parse_pax_time("1714557600.1234567891"),
+            Some(FileTime::from_unix_time(1714557600, 123456789))
+        );
+        assert_eq!(
+            parse_pax_time("1714557600"),
+            Some(FileTime::from_unix_time(1714557600, 0))
+        );
+        assert_eq!(parse_pax_time("-1.5"), None);
+        assert_eq!(parse_pax_time("1714557600.12a"), None);
+        assert_eq!(parse_pax_time(""), None);
+    }
+
+    #[test]
+    fn parses_timestamps() {
+        assert_eq!(
+            parse_timestamp("1714557600"),
+            Ok(FileTime::from_unix_time(1714557600, 0))
+        );
+        assert_eq!(
+            parse_timestamp("2024-05-01T10:00:00Z"),
+            Ok(FileTime::from_unix_time(1714557600, 0))
+        );
+        assert!(parse_timestamp("yesterday").is_err());
+    }
+
+    #[test]
+    fn restores_archived_mtime_to_the_nanosecond() {
+        let dir = tempfile::tempdir().unwrap();
+        let mtime = FileTime::from_unix_time(1714557600, 123456789);
+        let data = archive_with_mtime(dir.path(), mtime);
+
+        let root = dir.path().join("restored");
+        unpack_archive(&data, &root, &restore_args(None)).unwrap();
+
+        for path in [root.join("file"), root] {
+            let metadata = std::fs::metadata(&path).unwrap();
+            assert_eq!(FileTime::from_last_modification_time(&metadata), mtime);
+        }
+    }
+
+    #[test]
+    fn restores_fixed_mtime() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = archive_with_mtime(dir.path(), FileTime::from_unix_time(1714557600, 5));
+
+        let root = dir.path().join("restored");
+        let fixed = FileTime::from_unix_time(1700000000, 0);
+        unpack_archive(&data, &root, &restore_args(Some(fixed))).unwrap();
+
+        let metadata = std::fs::metadata(root.join("file")).unwrap();
+        assert_eq!(FileTime::from_last_modification_time(&metadata), fixed);
+    }
+
+    #[test]
+    fn extracts_links_inside_the_cached_path() {
+        let dir = tempfile::tempdir().unwrap();
+        extract(
+            dir.path(),
+            &[
+                Entry::Dir("0123abcd"),
+                Entry::Dir("0123abcd/deep"),
+                Entry::File("0123abcd/deep/file"),
+                Entry::Symlink("0123abcd/deep/up", ".."),
+                Entry::Symlink("0123abcd/link", "deep/up/deep/file"),
+                Entry::HardLink("0123abcd/copy", "0123abcd/deep/file"),
+            ],
+        )
+        .unwrap();
+        let root = dir.path().join("cached");
+        assert_eq!(std::fs::read(root.join("link")).unwrap(), b"data");
+        assert_eq!(std::fs::read(root.join("copy")).unwrap(), b"data");
+    }
+
+    #[test]
+    fn rejects_parent_directory_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let err = extract(
+            dir.path(),
+            &[Entry::Dir("0123abcd"), Entry::File("0123abcd/../escaped")],
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains(".."), "{}", err);
+        assert!(!dir.path().join("escaped").exists());
+    }
+
+    #[test]
+    fn rejects_absolute_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("escaped");
+        let path = target.to_str().unwrap();
+        let err = extract(dir.path(), &[Entry::Dir("0123abcd"), Entry::File(path)]).unwrap_err();
+        assert!(err.to_string().contains("not below"), "{}", err);
+        assert!(!target.exists());
+    }
+
+    #[test]
+    fn rejects_symlinks_outside() {
+        let dir = tempfile::tempdir().unwrap();
+        for target in ["../outside", "/etc"] {
+            assert!(
+                extract(
+                    dir.path(),
+                    &[Entry::Dir("0123abcd"), Entry::Symlink("0123abcd/l", target)],
+                )
+                .is_err(),
+                "{}",
+                target
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_paths_through_symlinks() {
+        let dir = tempfile::tempdir().unwrap();
+        let err = extract(
+            dir.path(),
+            &[
+                Entry::Dir("0123abcd"),
+                Entry::Dir("0123abcd/sub"),
+                Entry::Symlink("0123abcd/l", "sub"),
+                Entry::File("0123abcd/l/file"),
+            ],
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("symbolic link"), "{}", err);
+    }
+
+    #[test]
+    fn rejects_symlink_chains_leading_outside() {
+        let dir = tempfile::tempdir().unwrap();
+        let err = extract(
+            dir.path(),
+            &[
+                Entry::Dir("0123abcd"),
+                Entry::Dir("0123abcd/deep"),
+                Entry::Symlink("0123abcd/deep/l", ".."),
+                Entry::Symlink("0123abcd/x", "deep/l/.."),
+            ],
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("outside"), "{}", err);
+        let root = dir.path().join("cached");
+        assert!(std::fs::symlink_metadata(root.join("x")).is_err());
+    }
+
+    #[test]
+    fn rejects_symlink_chains_in_any_order() {
+        let dir = tempfile::tempdir().unwrap();
+        // The link gone through is extracted after the one going through it
+        let err = extract(
+            dir.path(),
+            &[
+                Entry::Dir("0123abcd"),
+                Entry::Dir("0123abcd/deep"),
+                Entry::Symlink("0123abcd/x", "deep/l/.."),
+                Entry::Symlink("0123abcd/deep/l", ".."),
+            ],
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("outside"), "{}", err);
+    }
+
+    #[test]
+    fn rejects_directories_over_symlinks() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("cached");
+        std::fs::create_dir(&root).unwrap();
+        let permissions = std::fs::metadata(dir.path()).unwrap().permissions();
+        let err = extract(
+            dir.path(),
+            &[
+                Entry::Dir("0123abcd"),
+                Entry::Symlink("0123abcd/b", "."),
+                Entry::Symlink("0123abcd/a", "b/.."),
+                Entry::Dir("0123abcd/a"),
+            ],
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("symbolic link"), "{}", err);
+        assert_eq!(
+            std::fs::metadata(dir.path()).unwrap().permissions(),
+            permissions
+        );
+    }
+
+    #[test]
+    fn replaces_symlinks_with_files() {
+        let dir = tempfile::tempdir().unwrap();
+        extract(
+            dir.path(),
+            &[
+                Entry::Dir("0123abcd"),
+                Entry::Symlink("0123abcd/l", "target"),
+                Entry::File("0123abcd/l"),
+            ],
+        )
+        .unwrap();
+        let root = dir.path().join("cached");
+        assert!(
+            !std::fs::symlink_metadata(root.join("l"))
+                .unwrap()
+                .is_symlink()
+        );
+        assert_eq!(std::fs::read(root.join("l")).unwrap(), b"data");
+        assert!(!root.join("target").exists());
+    }
+
+    #[test]
+    fn rejects_hard_links_outside() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("secret"), b"secret").unwrap();
+        for target in ["0123abcd/../secret", "other/secret", "/etc/passwd"] {
+            assert!(
+                extract(
+                    dir.path(),
+                    &[
+                        Entry::Dir("0123abcd"),
+                        Entry::HardLink("0123abcd/h", target)
+                    ],
+                )
+                .is_err(),
+                "{}",
+                target
+            );
+        }
+        assert!(!dir.path().join("cached/h").exists());
+    }
+
+    #[test]
+    fn rejects_hard_links_to_symlinks() {
+        let dir = tempfile::tempdir().unwrap();
+        let err = extract(
+            dir.path(),
+            &[
+                Entry::Dir("0123abcd"),
+                Entry::Dir("0123abcd/deep"),
+                Entry::Symlink("0123abcd/deep/up", ".."),
+                Entry::HardLink("0123abcd/h", "0123abcd/deep/up"),
+            ],
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("symbolic link"), "{}", err);
+    }
+}